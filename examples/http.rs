@@ -1,62 +1,100 @@
 extern crate tk_pool;
-extern crate tk_http;
 extern crate futures;
-extern crate abstract_ns;
-extern crate futures_cpupool;
-extern crate tokio_core;
+extern crate tokio;
+extern crate tokio_util;
+extern crate bytes;
 extern crate env_logger;
-extern crate ns_std_threaded;
-extern crate ns_router;
 extern crate log;
 
 use std::env;
-use std::time::Duration;
+use std::io;
+use std::net::SocketAddr;
 
-use abstract_ns::HostResolve;
-use futures::{Future, Sink};
+use bytes::BytesMut;
+use futures::TryFutureExt;
 use futures::future::join_all;
-use tk_pool::{pool_for};
-use tk_http::client::{Proto, Config as HConfig, Client, Error};
-use ns_router::SubscribeExt;
+use tokio::net::{TcpStream, lookup_host};
+use tokio::runtime::Runtime;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tk_pool::pool_for;
+use tk_pool::call::{Call, Pipelined};
+use tk_pool::queue::Pool;
+
+/// A minimal HTTP/1.1 client codec: sends GET requests for a path and
+/// receives bodies of responses with `Content-Length`
+struct Codec;
+
+impl Encoder<String> for Codec {
+    type Error = io::Error;
+    fn encode(&mut self, path: String, buf: &mut BytesMut)
+        -> Result<(), io::Error>
+    {
+        buf.extend_from_slice(format!(
+            "GET {} HTTP/1.1\r\nHost: httpbin.org\r\n\r\n", path).as_bytes());
+        Ok(())
+    }
+}
+
+impl Decoder for Codec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+    fn decode(&mut self, buf: &mut BytesMut)
+        -> Result<Option<Vec<u8>>, io::Error>
+    {
+        let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end + 4,
+            None => return Ok(None),
+        };
+        let length = String::from_utf8_lossy(&buf[..end]).lines()
+            .filter_map(|line| {
+                let mut pair = line.splitn(2, ':');
+                let name = pair.next()?.trim();
+                if !name.eq_ignore_ascii_case("content-length") {
+                    return None;
+                }
+                pair.next()?.trim().parse::<usize>().ok()
+            })
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                          "no content-length"))?;
+        if buf.len() < end + length {
+            return Ok(None);
+        }
+        let mut response = buf.split_to(end + length);
+        Ok(Some(response.split_off(end).to_vec()))
+    }
+}
 
 fn main() {
-    if let Err(_) = env::var("RUST_LOG") {
+    if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "warn");
     }
     env_logger::init();
 
-    let mut lp = tokio_core::reactor::Core::new().unwrap();
-    let h1 = lp.handle();
-    let h2 = lp.handle();
-
-    let ns = ns_router::Router::from_config(&ns_router::Config::new()
-        .set_fallthrough(ns_std_threaded::ThreadedResolver::new()
-            .null_service_resolver()
-            .interval_subscriber(Duration::new(1, 0), &h1))
-        .done(),
-        &lp.handle());
+    let rt = Runtime::new().unwrap();
+    let addresses = rt.block_on(lookup_host("httpbin.org:80"))
+        .expect("httpbin.org is resolved")
+        .collect::<Vec<SocketAddr>>();
 
-    let connection_config = HConfig::new()
-        .inflight_request_limit(1)
-        .done();
-    let mut pool =
-        pool_for(move |addr| Proto::connect_tcp(addr, &connection_config, &h2))
-        .connect_to(ns.subscribe_many(&["httpbin.org"], 80))
+    let (mut pool, _shutdown) = pool_for(|addr| {
+            TcpStream::connect(addr)
+            .map_ok(|sock| Pipelined::new(Framed::new(sock, Codec), 1))
+        })
+        .connect_to_static(&addresses)
+        .name("httpbin")
         .lazy_uniform_connections(2)
         .with_queue_size(16)
-        .spawn_on(&lp.handle())
-        // This is needed for Client trait, (i.e. so that `.fetch_url()` works)
-        // May be fixed in tk-http in future
-        .sink_map_err(|_| Error::custom("Can't send request"));
+        .spawn_on(rt.handle());
+    let pool: &mut Pool<Call<String, Vec<u8>>, _> = &mut pool;
 
-    // Alternative config (not implemented)
-    // let mut pool =
-    //     pool_for(|addr| Proto::connect_tcp(addr, &connection_config, &h2))
-    //     .with_config_stream(ns, once_and_wait(Config::new()
-    //         .set_name(&["httpbin.org:80"])
-    //         .lazy_uniform_connections(2)
-    //         .with_queue_size(10)))
-    //     .spawn_on(&lp.handle());
+    // Connection limit and reconnect timeout may be changed at runtime:
+    // let (tx, rx) = futures::channel::mpsc::unbounded();
+    // let (pool, _shutdown) = pool_for(connector)
+    //     .connect_to_static(&addresses)
+    //     .lazy_uniform_connections(2)
+    //     .config_stream(rx)
+    //     .spawn_on(rt.handle());
+    // tx.unbounded_send(tk_pool::uniform::Config::new(4));
 
     println!("We will send 16 requests over 1 connection per ip. \
               Each requests hangs for 5 seconds at the server side \
@@ -64,13 +102,13 @@ fn main() {
               many IPs, expect script to finish in 5 or 10 seconds \
               with first response coming in 5 seconds.");
 
-    lp.run(futures::lazy(|| {
-        join_all((0..16).map(move |_| {
-            pool
-            .fetch_url("http://httpbin.org/delay/5")
-            .map(|r| {
-                println!("Received {} bytes", r.body().len())
-            })
-        }))
-    })).unwrap();
+    let responses = (0..16).map(|_| {
+        pool.call("/delay/5".to_string())
+        .map_ok(|body| println!("Received {} bytes", body.len()))
+    }).collect::<Vec<_>>();
+    for result in rt.block_on(join_all(responses)) {
+        if let Err(e) = result {
+            println!("Request failed: {}", e);
+        }
+    }
 }
@@ -0,0 +1,186 @@
+//! Address types connection pool might connect to
+//!
+//! By default pool connects to `SocketAddr` values received from name
+//! service (``abstract_ns::Address``), but any type implementing
+//! ``PoolAddress`` might be used as an address, e.g. a path to a Unix
+//! socket of a local sidecar or an opaque endpoint id resolved by the
+//! connector itself:
+//!
+//! ```rust,ignore
+//! let (pool, shutdown) = pool_for(connect_fn(|path: UnixPath| {
+//!         UnixStream::connect(path.path().to_owned())
+//!             .map_ok(|sock| Framed::new(sock, Codec))
+//!     }))
+//!     .connect_to(stream::once(ready(vec![UnixPath::new("/run/app.sock")]))
+//!                 .chain(stream::pending()))
+//!     .lazy_uniform_connections(2)
+//!     .spawn_on(&Handle::current());
+//! ```
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use abstract_ns::Address;
+use futures::Stream;
+
+
+/// An address connection pool might connect to
+///
+/// Pool keeps per-address connection counters and blacklists failing
+/// addresses, so address must be hashable, and it's displayed in logs.
+pub trait PoolAddress: Clone + Eq + Hash + fmt::Display + fmt::Debug
+    + Send + Sync + 'static
+{
+    /// Returns address as reported in ``events::Event``
+    ///
+    /// By default address is converted into a string.
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Other(self.to_string())
+    }
+}
+
+/// A set of addresses received from an address stream
+///
+/// Every item of the stream passed to ``PartialConfig::connect_to``
+/// replaces the whole set of addresses.
+pub trait AddressSet {
+    /// Type of addresses in the set
+    type Addr: PoolAddress;
+    /// Returns all addresses pool should connect to
+    fn addresses(&self) -> HashSet<Self::Addr>;
+}
+
+/// Address as reported in ``events::Event``
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// An IP address and a port
+    Socket(SocketAddr),
+    /// A path to a Unix socket
+    Unix(PathBuf),
+    /// Any other address type (displayed as a string)
+    Other(String),
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// An address stream which yields a single value and never ends
+///
+/// Created by ``PartialConfig::connect_to_static`` and
+/// ``PartialConfig::connect_to_addr``.
+#[derive(Debug, Clone)]
+pub struct StaticAddress<T> {
+    value: Option<T>,
+}
+
+/// A path to a Unix socket used as an address
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UnixPath(PathBuf);
+
+impl UnixPath {
+    /// Create an address from a path
+    pub fn new<P: Into<PathBuf>>(path: P) -> UnixPath {
+        UnixPath(path.into())
+    }
+    /// Returns path to the socket
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl<T> StaticAddress<T> {
+    /// Create a stream yielding this address set
+    pub fn new(value: T) -> StaticAddress<T> {
+        StaticAddress {
+            value: Some(value),
+        }
+    }
+}
+
+impl<T> Unpin for StaticAddress<T> {}
+
+impl<T> Stream for StaticAddress<T> {
+    type Item = T;
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context)
+        -> Poll<Option<T>>
+    {
+        match self.value.take() {
+            Some(value) => Poll::Ready(Some(value)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl fmt::Display for UnixPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unix:{}", self.0.display())
+    }
+}
+
+impl PoolAddress for SocketAddr {
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Socket(*self)
+    }
+}
+
+impl PoolAddress for UnixPath {
+    fn endpoint(&self) -> Endpoint {
+        Endpoint::Unix(self.0.clone())
+    }
+}
+
+impl PoolAddress for String {}
+
+impl AddressSet for Address {
+    type Addr = SocketAddr;
+    fn addresses(&self) -> HashSet<SocketAddr> {
+        // only addresses of the highest priority are used
+        self.at(0).addresses().collect()
+    }
+}
+
+impl<P: PoolAddress> AddressSet for Vec<P> {
+    type Addr = P;
+    fn addresses(&self) -> HashSet<P> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<P: PoolAddress> AddressSet for HashSet<P> {
+    type Addr = P;
+    fn addresses(&self) -> HashSet<P> {
+        self.clone()
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Socket(ref addr) => fmt::Display::fmt(addr, f),
+            Endpoint::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Other(ref name) => f.write_str(name),
+            Endpoint::__Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::task::{Context, Poll};
+    use futures::StreamExt;
+    use futures::task::noop_waker_ref;
+    use super::StaticAddress;
+
+    #[test]
+    fn static_address() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut stream = StaticAddress::new(vec![1u32]);
+        assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Ready(Some(vec![1])));
+        assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Pending);
+        assert_eq!(stream.poll_next_unpin(&mut cx), Poll::Pending);
+    }
+}
@@ -0,0 +1,464 @@
+//! A queue with overflow policy and queue discipline
+//!
+//! Used instead of `futures::channel::mpsc` channel when either
+//! ``queue::Overflow`` or ``queue::Discipline`` is not the default one.
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use futures::task::AtomicWaker;
+
+use queue::{Overflow, Discipline};
+
+
+pub struct Sender<V> {
+    shared: Arc<Shared<V>>,
+}
+
+pub struct Receiver<V> {
+    shared: Arc<Shared<V>>,
+}
+
+pub enum SendError<V> {
+    /// Queue is full, with ``Overflow::Backpressure`` parked task is
+    /// woken up when there is a space in the queue
+    Full(V),
+    /// Receiver is closed or dropped
+    Closed(V),
+}
+
+struct Shared<V> {
+    state: Mutex<State<V>>,
+    task: AtomicWaker,
+    size: usize,
+    policy: Overflow,
+    discipline: Discipline,
+    on_drop: Arc<dyn Fn(V) + Send + Sync>,
+}
+
+struct State<V> {
+    queue: VecDeque<(Instant, V)>,
+    senders: usize,
+    closed: bool,
+    /// Senders waiting for a space in the queue
+    waiting: Vec<Waker>,
+    /// Minimum delay of the oldest request in the current interval
+    min_delay: Option<Duration>,
+    interval_end: Option<Instant>,
+    overloaded: bool,
+    codel: Codel,
+}
+
+/// State of the CoDel algorithm (RFC 8289)
+struct Codel {
+    /// Time when delay has been above target for an interval, set while
+    /// delay is above target
+    first_above: Option<Instant>,
+    dropping: bool,
+    drop_next: Instant,
+    /// Number of items dropped since entering the dropping state
+    count: u32,
+    last_count: u32,
+}
+
+pub fn channel<V>(size: usize, policy: Overflow, discipline: Discipline,
+    on_drop: Arc<dyn Fn(V) + Send + Sync>)
+    -> (Sender<V>, Receiver<V>)
+{
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State::new(Instant::now())),
+        task: AtomicWaker::new(),
+        size, policy, discipline, on_drop,
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<V> Shared<V> {
+    fn lock(&self) -> MutexGuard<'_, State<V>> {
+        self.state.lock().expect("queue is not poisoned")
+    }
+}
+
+impl<V> State<V> {
+    fn new(now: Instant) -> State<V> {
+        State {
+            queue: VecDeque::new(),
+            senders: 1,
+            closed: false,
+            waiting: Vec::new(),
+            min_delay: None,
+            interval_end: None,
+            overloaded: false,
+            codel: Codel {
+                first_above: None,
+                dropping: false,
+                drop_next: now,
+                count: 0,
+                last_count: 0,
+            },
+        }
+    }
+    /// Tracks standing queue delay, queue is overloaded if delay of the
+    /// oldest request was above target for the whole interval
+    fn check_overload(&mut self, now: Instant,
+        target: Duration, interval: Duration)
+    {
+        let delay = match self.queue.front() {
+            Some(&(queued, _)) => now.duration_since(queued),
+            None => {
+                // there is no standing queue, measurement starts again
+                // with the next request
+                self.min_delay = None;
+                self.interval_end = None;
+                self.overloaded = false;
+                return;
+            }
+        };
+        self.min_delay = Some(match self.min_delay {
+            Some(old) => min(old, delay),
+            None => delay,
+        });
+        match self.interval_end {
+            Some(end) if end > now => {}
+            Some(_) => {
+                self.overloaded = self.min_delay > Some(target);
+                // current sample also starts the next interval
+                self.min_delay = Some(delay);
+                self.interval_end = Some(now + interval);
+            }
+            None => self.interval_end = Some(now + interval),
+        }
+    }
+    /// Takes the oldest item, returns true if it's ok to drop it, i.e.
+    /// delay has been above target for at least an interval
+    fn codel_dequeue(&mut self, now: Instant,
+        target: Duration, interval: Duration)
+        -> Option<(V, bool)>
+    {
+        let (queued, item) = match self.queue.pop_front() {
+            Some(pair) => pair,
+            None => {
+                self.codel.first_above = None;
+                return None;
+            }
+        };
+        let mut ok_to_drop = false;
+        if now.duration_since(queued) < target {
+            self.codel.first_above = None;
+        } else {
+            match self.codel.first_above {
+                Some(time) => ok_to_drop = now >= time,
+                None => self.codel.first_above = Some(now + interval),
+            }
+        }
+        Some((item, ok_to_drop))
+    }
+    /// CoDel dequeue: while delay stays above target, one item is dropped
+    /// every `interval / sqrt(count)`
+    fn codel_pop(&mut self, now: Instant,
+        target: Duration, interval: Duration, dropped: &mut Vec<V>)
+        -> Option<V>
+    {
+        let (mut item, ok_to_drop) =
+            match self.codel_dequeue(now, target, interval) {
+                Some(pair) => pair,
+                None => {
+                    self.codel.dropping = false;
+                    return None;
+                }
+            };
+        if self.codel.dropping {
+            if !ok_to_drop {
+                self.codel.dropping = false;
+            }
+            while self.codel.dropping && now >= self.codel.drop_next {
+                dropped.push(item);
+                self.codel.count += 1;
+                match self.codel_dequeue(now, target, interval) {
+                    Some((next, true)) => {
+                        item = next;
+                        self.codel.drop_next = control_law(
+                            self.codel.drop_next, self.codel.count,
+                            interval);
+                    }
+                    Some((next, false)) => {
+                        item = next;
+                        self.codel.dropping = false;
+                    }
+                    None => {
+                        self.codel.dropping = false;
+                        return None;
+                    }
+                }
+            }
+        } else if ok_to_drop {
+            dropped.push(item);
+            self.codel.dropping = true;
+            // if dropping state is entered soon after leaving it, continue
+            // with the drop rate reached last time
+            let delta = self.codel.count.wrapping_sub(self.codel.last_count);
+            self.codel.count = if delta > 1 &&
+                now < self.codel.drop_next + interval * 16
+            {
+                delta
+            } else {
+                1
+            };
+            self.codel.drop_next = control_law(now, self.codel.count,
+                interval);
+            self.codel.last_count = self.codel.count;
+            item = match self.codel_dequeue(now, target, interval) {
+                Some((next, _)) => next,
+                None => return None,
+            };
+        }
+        Some(item)
+    }
+    /// Returns next item to send and items dropped by the discipline
+    fn pop(&mut self, now: Instant, discipline: &Discipline,
+        dropped: &mut Vec<V>)
+        -> Option<V>
+    {
+        match *discipline {
+            Discipline::Codel { target, interval } => {
+                self.codel_pop(now, target, interval, dropped)
+            }
+            Discipline::AdaptiveLifo { target, interval } => {
+                self.check_overload(now, target, interval);
+                if self.overloaded {
+                    self.queue.pop_back().map(|(_, item)| item)
+                } else {
+                    self.queue.pop_front().map(|(_, item)| item)
+                }
+            }
+            _ => self.queue.pop_front().map(|(_, item)| item),
+        }
+    }
+}
+
+fn control_law(time: Instant, count: u32, interval: Duration) -> Instant {
+    time + interval.div_f64(f64::from(count).sqrt())
+}
+
+impl<V> Sender<V> {
+    /// Put item into the queue, returns an item dropped due to overflow
+    ///
+    /// If `park` is set and queue is full, the waker is woken up when
+    /// there is a space in the queue.
+    pub fn send(&self, item: V, park: Option<&Waker>)
+        -> Result<Option<V>, SendError<V>>
+    {
+        let dropped = {
+            let mut state = self.shared.lock();
+            if state.closed {
+                return Err(SendError::Closed(item));
+            }
+            if state.queue.len() < self.shared.size {
+                state.queue.push_back((Instant::now(), item));
+                None
+            } else {
+                match self.shared.policy {
+                    Overflow::DropOldest => {
+                        let oldest = state.queue.pop_front();
+                        state.queue.push_back((Instant::now(), item));
+                        oldest.map(|(_, item)| item)
+                    }
+                    Overflow::DropNewest => return Ok(Some(item)),
+                    Overflow::Backpressure if park.is_some() => {
+                        let waker = park.expect("checked above");
+                        if !state.waiting.iter().any(|w| w.will_wake(waker)) {
+                            state.waiting.push(waker.clone());
+                        }
+                        return Err(SendError::Full(item));
+                    }
+                    _ => return Err(SendError::Full(item)),
+                }
+            }
+        };
+        self.shared.task.wake();
+        Ok(dropped)
+    }
+    /// Overflow policy of the queue
+    pub fn policy(&self) -> Overflow {
+        self.shared.policy
+    }
+    /// Pass the item to the drop callback
+    pub fn dropped(&self, item: V) {
+        (self.shared.on_drop)(item);
+    }
+}
+
+impl<V> Receiver<V> {
+    /// Stop accepting new items, queued items are still received
+    pub fn close(&mut self) {
+        let waiting = {
+            let mut state = self.shared.lock();
+            state.closed = true;
+            state.waiting.drain(..).collect::<Vec<_>>()
+        };
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+}
+
+impl<V> Stream for Receiver<V> {
+    type Item = V;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<V>> {
+        let mut dropped = Vec::new();
+        let (result, waiting) = {
+            let mut state = self.shared.lock();
+            let result = match state.pop(Instant::now(),
+                                         &self.shared.discipline,
+                                         &mut dropped)
+            {
+                Some(item) => Poll::Ready(Some(item)),
+                None if state.closed || state.senders == 0 => {
+                    Poll::Ready(None)
+                }
+                None => {
+                    // registered under the lock, so no item is missed
+                    self.shared.task.register(cx.waker());
+                    Poll::Pending
+                }
+            };
+            let waiting = if state.queue.len() < self.shared.size {
+                state.waiting.drain(..).collect()
+            } else {
+                Vec::new()
+            };
+            (result, waiting)
+        };
+        for waker in waiting {
+            waker.wake();
+        }
+        for item in dropped {
+            (self.shared.on_drop)(item);
+        }
+        result
+    }
+}
+
+impl<V> Clone for Sender<V> {
+    fn clone(&self) -> Sender<V> {
+        self.shared.lock().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<V> Drop for Sender<V> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.shared.lock();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.shared.task.wake();
+        }
+    }
+}
+
+impl<V> Drop for Receiver<V> {
+    fn drop(&mut self) {
+        self.close();
+        self.shared.lock().queue.clear();
+    }
+}
+
+impl<V> fmt::Debug for Sender<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("size", &self.shared.size)
+            .field("policy", &self.shared.policy)
+            .field("discipline", &self.shared.discipline)
+            .finish()
+    }
+}
+
+impl<V> fmt::Debug for Receiver<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("size", &self.shared.size)
+            .field("policy", &self.shared.policy)
+            .field("discipline", &self.shared.discipline)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use std::task::{Context, Poll};
+    use futures::StreamExt;
+    use futures::task::noop_waker_ref;
+    use queue::{Overflow, Discipline};
+    use super::{channel, State};
+
+    #[test]
+    fn drop_oldest() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = channel(2, Overflow::DropOldest,
+            Discipline::Fifo, Arc::new(|_| {}));
+        assert_eq!(tx.send(1, None).ok(), Some(None));
+        assert_eq!(tx.send(2, None).ok(), Some(None));
+        assert_eq!(tx.send(3, None).ok(), Some(Some(1)));
+        assert_eq!(rx.poll_next_unpin(&mut cx), Poll::Ready(Some(2)));
+        assert_eq!(rx.poll_next_unpin(&mut cx), Poll::Ready(Some(3)));
+        assert_eq!(rx.poll_next_unpin(&mut cx), Poll::Pending);
+        drop(tx);
+        assert_eq!(rx.poll_next_unpin(&mut cx), Poll::Ready(None));
+    }
+
+    fn queued(now: Instant, num: u32) -> State<u32> {
+        let mut state = State::new(now);
+        state.queue.extend((0..num).map(|i| (now, i)));
+        state
+    }
+
+    #[test]
+    fn adaptive_lifo() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let lifo = Discipline::AdaptiveLifo {
+            target: ms(1),
+            interval: ms(5),
+        };
+        let mut state = queued(start, 5);
+        let mut dropped = Vec::new();
+        assert_eq!(state.pop(start, &lifo, &mut dropped), Some(0));
+        // delay was below target at the start of the interval
+        assert_eq!(state.pop(start + ms(10), &lifo, &mut dropped), Some(1));
+        // oldest request waited longer than target for the interval
+        assert_eq!(state.pop(start + ms(20), &lifo, &mut dropped), Some(4));
+        assert_eq!(state.pop(start + ms(20), &lifo, &mut dropped), Some(3));
+        assert_eq!(dropped, Vec::<u32>::new());
+    }
+
+    #[test]
+    fn codel() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let codel = Discipline::Codel {
+            target: ms(1),
+            interval: ms(20),
+        };
+        let mut state = queued(start, 10);
+        let mut dropped = Vec::new();
+        assert_eq!(state.pop(start, &codel, &mut dropped), Some(0));
+        // delay is above target, but not for the whole interval yet
+        assert_eq!(state.pop(start + ms(40), &codel, &mut dropped), Some(1));
+        assert_eq!(state.pop(start + ms(80), &codel, &mut dropped), Some(3));
+        // next drop is scheduled an interval later
+        assert_eq!(state.pop(start + ms(80), &codel, &mut dropped), Some(4));
+        assert_eq!(state.pop(start + ms(105), &codel, &mut dropped),
+                   Some(6));
+        assert_eq!(dropped, vec![2, 5]);
+    }
+}
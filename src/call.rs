@@ -0,0 +1,555 @@
+//! Request/response layer on top of the pool
+//!
+//! Connection pool is just a `Sink`, so by default it's up to the protocol
+//! implementation how to deliver a response back to the caller. This module
+//! provides a generic way to do that: every request is bundled with a
+//! oneshot channel for the reply (``Call``), so you can use a pool of
+//! ``Call`` items like a service:
+//!
+//! ```rust,ignore
+//! let (mut pool, _shutdown) = pool_for(|addr| {
+//!         TcpStream::connect(addr)
+//!         .map_ok(|sock| Pipelined::new(Framed::new(sock, Codec), 16))
+//!     })
+//!     .connect_to(address_stream)
+//!     .lazy_uniform_connections(2)
+//!     .spawn_on(&Handle::current());
+//! let response = pool.call(request).await?;
+//! ```
+//!
+//! Connection sink receives ``Call`` items and must eventually call
+//! ``Reply::send``. ``Pipelined`` implements that for transports which
+//! return responses in the same order requests were sent.
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::{FutureExt, Sink, Stream, StreamExt};
+use futures::channel::oneshot;
+
+use concurrency::{Completion, Tracked};
+use fail_fast::{Rejectable, NoHealthyBackends};
+use metrics::Collect;
+use queue::{Pool, PoolError};
+use address::PoolAddress;
+use route::{Route, Hint};
+
+
+/// A request bundled with a channel for the response
+///
+/// Type parameter `P` is the address type of the pool (see
+/// ``address::PoolAddress``).
+#[derive(Debug)]
+pub struct Call<Req, Resp, P=SocketAddr> {
+    request: Req,
+    reply: Reply<Resp>,
+    hint: Hint<P>,
+    peer: Option<Peer<P>>,
+}
+
+/// Address of the original request shared with its hedge
+#[derive(Debug)]
+pub(crate) enum Peer<P> {
+    /// Remember address the request is dispatched to
+    Record(Arc<Mutex<Option<P>>>),
+    /// Avoid address the original request is dispatched to
+    Avoid(Arc<Mutex<Option<P>>>),
+}
+
+/// A sending side of the response channel
+///
+/// If it's dropped without sending a response, caller receives
+/// ``CallError::Canceled``.
+///
+/// With ``PoolConfig::adaptive_concurrency`` sending a response reports
+/// a successful request, and dropping the reply reports a failure.
+#[derive(Debug)]
+pub struct Reply<Resp> {
+    tx: Option<oneshot::Sender<Result<Resp, CallError>>>,
+    completion: Option<Completion>,
+}
+
+/// A future returned by ``Pool::call``
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct ResponseFuture<Resp> {
+    state: State<Resp>,
+}
+
+#[derive(Debug)]
+enum State<Resp> {
+    Waiting(oneshot::Receiver<Result<Resp, CallError>>),
+    Failed(CallError),
+    Done,
+}
+
+/// Error returned by ``ResponseFuture``
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    /// Queue of the pool is full, request is not sent
+    QueueFull,
+    /// Pool is closed, request is not sent
+    PoolClosed,
+    /// Request was dropped without response
+    ///
+    /// Usually this means that connection was closed before response was
+    /// received. Request might have been processed by the peer.
+    Canceled,
+    /// There are no healthy backends, request is not sent
+    ///
+    /// Only returned if pool is configured with ``PoolConfig::fail_fast``.
+    NoHealthyBackends,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// A connection sink adapter for pipelined request/response protocols
+///
+/// Wraps a transport which is both a `Sink` of requests and a `Stream`
+/// of responses (or errors), responses are matched with requests in order.
+#[derive(Debug)]
+pub struct Pipelined<S, Resp, P=SocketAddr> {
+    transport: S,
+    in_flight: VecDeque<Reply<Resp>>,
+    max_in_flight: usize,
+    phantom: PhantomData<fn(P)>,
+}
+
+/// Error of the connection wrapped into ``Pipelined``
+#[derive(Debug)]
+pub enum PipelineError<E> {
+    /// Transport error
+    Transport(E),
+    /// Connection closed by peer
+    Closed,
+    /// Response received when there are no requests in flight
+    UnsolicitedResponse,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+impl<Req, Resp, P> Call<Req, Resp, P> {
+    /// Create a call and a future which resolves to the response
+    ///
+    /// This is useful if you want to send calls using `Sink` interface
+    /// (and so wait for the queue if it's full) instead of ``Pool::call``.
+    pub fn new(request: Req) -> (Call<Req, Resp, P>, ResponseFuture<Resp>) {
+        let (tx, rx) = oneshot::channel();
+        let call = Call {
+            request,
+            reply: Reply { tx: Some(tx), completion: None },
+            hint: Hint::Any,
+            peer: None,
+        };
+        (call, ResponseFuture { state: State::Waiting(rx) })
+    }
+    /// Get a reference to the request
+    pub fn request(&self) -> &Req {
+        &self.request
+    }
+    /// Set a routing hint for the call
+    ///
+    /// Hint is only used if pool is configured with
+    /// ``PoolConfig::routing``.
+    pub fn with_hint(mut self, hint: Hint<P>) -> Call<Req, Resp, P> {
+        self.hint = hint;
+        self
+    }
+    /// Get a routing hint of the call
+    pub fn hint(&self) -> &Hint<P> {
+        &self.hint
+    }
+    /// Split the call into request and the reply channel
+    pub fn into_parts(self) -> (Req, Reply<Resp>) {
+        (self.request, self.reply)
+    }
+    pub(crate) fn with_peer(mut self, peer: Peer<P>) -> Call<Req, Resp, P> {
+        self.peer = Some(peer);
+        self
+    }
+}
+
+impl<Req, Resp, P: PoolAddress> Route<P> for Call<Req, Resp, P> {
+    fn pinned(&self) -> Option<P> {
+        self.hint.pinned()
+    }
+    fn is_excluded(&self, addr: &P) -> bool {
+        if self.hint.is_excluded(addr) {
+            return true;
+        }
+        match self.peer {
+            Some(Peer::Avoid(ref peer)) => {
+                peer.lock().expect("peer lock").as_ref() == Some(addr)
+            }
+            _ => false,
+        }
+    }
+    fn dispatched(&mut self, addr: &P) {
+        if let Some(Peer::Record(ref peer)) = self.peer {
+            *peer.lock().expect("peer lock") = Some(addr.clone());
+        }
+    }
+}
+
+impl<Req, Resp, P> Rejectable for Call<Req, Resp, P> {
+    fn rejected(error: NoHealthyBackends<Self>) {
+        let call = error.into_inner();
+        call.reply.fail(CallError::NoHealthyBackends);
+    }
+}
+
+impl<Req, Resp, P> Tracked for Call<Req, Resp, P> {
+    fn track(&mut self, completion: Completion) {
+        self.reply.completion = Some(completion);
+    }
+}
+
+impl<Resp> Reply<Resp> {
+    /// Send a response to the caller
+    ///
+    /// Returns response back if caller is not interested in it any more.
+    pub fn send(mut self, response: Resp) -> Result<(), Resp> {
+        if let Some(completion) = self.completion.take() {
+            completion.success();
+        }
+        let tx = self.tx.take().expect("reply is not sent yet");
+        tx.send(Ok(response)).map_err(|r| match r {
+            Ok(response) => response,
+            Err(_) => unreachable!(),
+        })
+    }
+    /// Returns true if the caller has dropped the future
+    pub fn is_canceled(&self) -> bool {
+        match self.tx {
+            Some(ref tx) => tx.is_canceled(),
+            None => true,
+        }
+    }
+    fn fail(mut self, err: CallError) {
+        if let Some(tx) = self.tx.take() {
+            tx.send(Err(err)).ok();
+        }
+    }
+}
+
+impl<Resp> Drop for Reply<Resp> {
+    fn drop(&mut self) {
+        if self.tx.is_some() {
+            if let Some(completion) = self.completion.take() {
+                completion.failure();
+            }
+        }
+    }
+}
+
+impl<Req, Resp, P, M: Collect> Pool<Call<Req, Resp, P>, M> {
+    /// Send a request and return a future of the response
+    ///
+    /// This method doesn't wait if queue is full, future resolves to
+    /// ``CallError::QueueFull`` instead. Use ``Call::new`` and the `Sink`
+    /// interface if you need to wait for the queue.
+    pub fn call(&mut self, request: Req) -> ResponseFuture<Resp> {
+        let (call, future) = Call::new(request);
+        self.send_call(call, future)
+    }
+    pub(crate) fn send_call(&mut self, call: Call<Req, Resp, P>,
+        future: ResponseFuture<Resp>)
+        -> ResponseFuture<Resp>
+    {
+        match self.try_send(call) {
+            Ok(()) => future,
+            Err(PoolError::QueueFull(_)) => ResponseFuture::failed(
+                CallError::QueueFull),
+            Err(_) => ResponseFuture::failed(CallError::PoolClosed),
+        }
+    }
+}
+
+impl<Resp> ResponseFuture<Resp> {
+    fn failed(err: CallError) -> ResponseFuture<Resp> {
+        ResponseFuture { state: State::Failed(err) }
+    }
+}
+
+impl<Resp> Future for ResponseFuture<Resp> {
+    type Output = Result<Resp, CallError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<Resp, CallError>>
+    {
+        let this = self.get_mut();
+        match this.state {
+            State::Waiting(ref mut rx) => match rx.poll_unpin(cx) {
+                Poll::Ready(Ok(Ok(resp))) => {
+                    this.state = State::Done;
+                    return Poll::Ready(Ok(resp));
+                }
+                Poll::Ready(Ok(Err(err))) => {
+                    this.state = State::Done;
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(oneshot::Canceled)) => {}
+            },
+            State::Failed(err) => {
+                this.state = State::Done;
+                return Poll::Ready(Err(err));
+            }
+            State::Done => panic!("future polled after completion"),
+        }
+        this.state = State::Done;
+        Poll::Ready(Err(CallError::Canceled))
+    }
+}
+
+impl<S, Resp, E> Pipelined<S, Resp>
+    where S: Stream<Item=Result<Resp, E>> + Unpin,
+{
+    /// Wrap a transport allowing up to `max_in_flight` requests sent
+    /// without a response
+    pub fn new(transport: S, max_in_flight: usize) -> Pipelined<S, Resp> {
+        Pipelined::for_address_type(transport, max_in_flight)
+    }
+}
+
+impl<S, Resp, E, P> Pipelined<S, Resp, P>
+    where S: Stream<Item=Result<Resp, E>> + Unpin,
+{
+    /// Same as ``new`` but for pools of other address types, e.g.
+    /// ``address::UnixPath``
+    pub fn for_address_type(transport: S, max_in_flight: usize)
+        -> Pipelined<S, Resp, P>
+    {
+        assert!(max_in_flight > 0);
+        Pipelined {
+            transport,
+            in_flight: VecDeque::new(),
+            max_in_flight,
+            phantom: PhantomData,
+        }
+    }
+    /// Number of requests waiting for responses
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+    fn poll_responses(&mut self, cx: &mut Context)
+        -> Result<(), PipelineError<E>>
+    {
+        loop {
+            match self.transport.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(resp))) => {
+                    match self.in_flight.pop_front() {
+                        // caller might have dropped the future, it's fine
+                        Some(reply) => { reply.send(resp).ok(); }
+                        None => return Err(PipelineError::UnsolicitedResponse),
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    return Err(PipelineError::Transport(e));
+                }
+                // in-flight replies are canceled when connection is dropped
+                Poll::Ready(None) => return Err(PipelineError::Closed),
+                Poll::Pending => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<S, Req, Resp, E, P> Sink<Call<Req, Resp, P>> for Pipelined<S, Resp, P>
+    where S: Sink<Req, Error=E> + Stream<Item=Result<Resp, E>> + Unpin,
+{
+    type Error = PipelineError<E>;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), Self::Error>>
+    {
+        let this = self.get_mut();
+        if this.in_flight.len() >= this.max_in_flight {
+            this.poll_responses(cx)?;
+            if this.in_flight.len() >= this.max_in_flight {
+                // woken up by the stream of responses
+                return Poll::Pending;
+            }
+        }
+        Pin::new(&mut this.transport).poll_ready(cx)
+            .map_err(PipelineError::Transport)
+    }
+    fn start_send(self: Pin<&mut Self>, call: Call<Req, Resp, P>)
+        -> Result<(), Self::Error>
+    {
+        let this = self.get_mut();
+        let Call { request, reply, .. } = call;
+        Pin::new(&mut this.transport).start_send(request)
+            .map_err(PipelineError::Transport)?;
+        this.in_flight.push_back(reply);
+        Ok(())
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), Self::Error>>
+    {
+        let this = self.get_mut();
+        let flushed = Pin::new(&mut this.transport).poll_flush(cx)
+            .map_err(PipelineError::Transport)?;
+        this.poll_responses(cx)?;
+        if flushed.is_ready() && this.in_flight.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), Self::Error>>
+    {
+        let this = self.get_mut();
+        // wait for responses to requests which are already sent
+        if !this.in_flight.is_empty() {
+            let flushed = Pin::new(&mut this.transport).poll_flush(cx);
+            if let Poll::Ready(Err(e)) = flushed {
+                return Poll::Ready(Err(PipelineError::Transport(e)));
+            }
+            this.poll_responses(cx)?;
+            if !this.in_flight.is_empty() {
+                return Poll::Pending;
+            }
+        }
+        Pin::new(&mut this.transport).poll_close(cx)
+            .map_err(PipelineError::Transport)
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CallError::*;
+        match *self {
+            QueueFull => f.write_str("connection pool queue is full"),
+            PoolClosed => f.write_str("connection pool is closed"),
+            Canceled => f.write_str("request canceled without response"),
+            NoHealthyBackends => f.write_str("no healthy backends"),
+            __Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+impl ::std::error::Error for CallError {
+    fn description(&self) -> &str {
+        "call error"
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for PipelineError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PipelineError::*;
+        match *self {
+            Transport(ref e) => fmt::Display::fmt(e, f),
+            Closed => f.write_str("connection closed by peer"),
+            UnsolicitedResponse => {
+                f.write_str("response received with no request in flight")
+            }
+            __Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+impl<E: ::std::error::Error> ::std::error::Error for PipelineError<E> {
+    fn description(&self) -> &str {
+        "pipelined connection error"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use futures::{FutureExt, Sink, SinkExt, Stream};
+    use futures::executor::block_on;
+    use futures::task::noop_waker_ref;
+    use address::UnixPath;
+    use route::Route;
+    use super::{Call, CallError, Peer, Pipelined};
+
+    /// Transport which responds with the request multiplied by ten
+    struct Mock(VecDeque<u32>);
+
+    impl Sink<u32> for Mock {
+        type Error = ();
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), ()>>
+        {
+            Poll::Ready(Ok(()))
+        }
+        fn start_send(self: Pin<&mut Self>, item: u32) -> Result<(), ()> {
+            self.get_mut().0.push_back(item * 10);
+            Ok(())
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), ()>>
+        {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), ()>>
+        {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Stream for Mock {
+        type Item = Result<u32, ()>;
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Option<Result<u32, ()>>>
+        {
+            match self.get_mut().0.pop_front() {
+                Some(x) => Poll::Ready(Some(Ok(x))),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    #[test]
+    fn pipelined() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut conn = Pipelined::new(Mock(VecDeque::new()), 2);
+        let (c1, f1) = Call::new(1);
+        let (c2, mut f2) = Call::new(2);
+        let (c3, f3) = Call::new(3);
+        assert!(conn.poll_ready_unpin(&mut cx).is_ready());
+        conn.start_send_unpin(c1).unwrap();
+        assert!(conn.poll_ready_unpin(&mut cx).is_ready());
+        conn.start_send_unpin(c2).unwrap();
+        assert_eq!(conn.in_flight(), 2);
+        drop(f3);
+        // responses are read when in-flight limit is reached
+        assert!(conn.poll_ready_unpin(&mut cx).is_ready());
+        conn.start_send_unpin(c3).unwrap();
+        assert_eq!(block_on(f1), Ok(10));
+        assert_eq!(f2.poll_unpin(&mut cx), Poll::Ready(Ok(20)));
+        assert!(conn.poll_flush_unpin(&mut cx).is_ready());
+        assert_eq!(conn.in_flight(), 0);
+    }
+
+    #[test]
+    fn canceled() {
+        let (call, fut) = Call::<u32, u32>::new(1);
+        let (request, reply) = call.into_parts();
+        assert_eq!(request, 1);
+        drop(reply);
+        assert_eq!(block_on(fut), Err(CallError::Canceled));
+    }
+
+    #[test]
+    fn avoid_unix_peer() {
+        let peer = Arc::new(Mutex::new(None));
+        let (call, _fut) = Call::<u32, u32, UnixPath>::new(1);
+        let mut call = call.with_peer(Peer::Record(peer.clone()));
+        call.dispatched(&UnixPath::new("/tmp/a.sock"));
+        let (hedge, _fut) = Call::<u32, u32, UnixPath>::new(1);
+        let hedge = hedge.with_peer(Peer::Avoid(peer));
+        assert!(hedge.is_excluded(&UnixPath::new("/tmp/a.sock")));
+        assert!(!hedge.is_excluded(&UnixPath::new("/tmp/b.sock")));
+    }
+}
@@ -0,0 +1,333 @@
+//! Adaptive limit of concurrent requests per host
+//!
+//! By default number of requests in flight is only limited by the
+//! pushback of connection sinks. With ``PoolConfig::adaptive_concurrency``
+//! every host has a limit of outstanding requests which is adjusted by
+//! observed latency and errors using additive increase, multiplicative
+//! decrease (AIMD). When all hosts are at their limit requests wait in the
+//! queue.
+//!
+//! The pool can't see when a request is completed, so requests must
+//! implement ``Tracked`` and report the outcome using ``Completion``,
+//! ``call::Call`` does that when reply is sent (success) or dropped
+//! (failure):
+//!
+//! ```rust,ignore
+//! let (mut pool, shutdown) = pool_for(connector)
+//!     .connect_to(address_stream)
+//!     .lazy_uniform_connections(2)
+//!     .adaptive_concurrency(Aimd::new()
+//!         .latency_threshold(Duration::from_millis(100)))
+//!     .spawn_on(&Handle::current());
+//! ```
+//!
+//! Current limit of every host is reported with
+//! ``Collect::concurrency_limit``.
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::task::AtomicWaker;
+
+use config::private::LimitPolicy;
+
+
+/// A request which reports when it's completed
+pub trait Tracked: Sized {
+    /// Called when request is handed to a connection
+    ///
+    /// Might be called multiple times if connection isn't able to accept
+    /// the request, the new completion replaces the old one.
+    fn track(&mut self, completion: Completion);
+}
+
+/// Handle used to report completion of a request
+///
+/// Dropping it without reporting only frees the slot of the host, so it
+/// doesn't affect the limit.
+pub struct Completion {
+    host: Arc<Host>,
+    started: Instant,
+    done: Arc<AtomicBool>,
+}
+
+/// The pool's side of ``Completion``
+///
+/// Frees the slot when dropped, unless request is accepted by connection.
+/// This is needed because a request might be handed back by connection
+/// and wait in the pool, while still holding a stale completion.
+pub(crate) struct Slot {
+    host: Arc<Host>,
+    done: Arc<AtomicBool>,
+    accepted: bool,
+}
+
+/// Additive increase, multiplicative decrease concurrency limit
+///
+/// Limit grows by one after a limit's worth of successful requests, and is
+/// multiplied by the backoff ratio on every failure or a request which
+/// took longer than the latency threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aimd {
+    initial: u32,
+    min: u32,
+    max: u32,
+    backoff: f64,
+    latency: Option<Duration>,
+}
+
+/// No limit of concurrent requests (default)
+#[derive(Debug, Clone)]
+pub struct NoLimit;
+
+/// Limit of a single host
+pub(crate) struct Host {
+    settings: Aimd,
+    state: Mutex<State>,
+    /// Pool task waiting for a free slot
+    task: Arc<AtomicWaker>,
+}
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+    changed: bool,
+    /// A slot is freed since the last `take_freed`
+    freed: bool,
+}
+
+impl Aimd {
+    /// Create a default limit
+    ///
+    /// Initial limit is 20 requests, it's kept between 1 and 1000, backoff
+    /// ratio is 0.9 and there is no latency threshold.
+    pub fn new() -> Aimd {
+        Aimd {
+            initial: 20,
+            min: 1,
+            max: 1000,
+            backoff: 0.9,
+            latency: None,
+        }
+    }
+    /// Set limit of a newly added host
+    pub fn initial_limit(mut self, limit: u32) -> Aimd {
+        assert!(limit > 0);
+        self.initial = limit;
+        self
+    }
+    /// Set bounds of the limit
+    pub fn limits(mut self, min: u32, max: u32) -> Aimd {
+        assert!(min > 0 && min <= max);
+        self.min = min;
+        self.max = max;
+        self
+    }
+    /// Set a ratio limit is multiplied by on failure
+    pub fn backoff_ratio(mut self, ratio: f64) -> Aimd {
+        assert!(ratio > 0. && ratio < 1.);
+        self.backoff = ratio;
+        self
+    }
+    /// Treat successful requests which took longer than this as failures
+    pub fn latency_threshold(mut self, latency: Duration) -> Aimd {
+        self.latency = Some(latency);
+        self
+    }
+}
+
+impl Default for Aimd {
+    fn default() -> Aimd {
+        Aimd::new()
+    }
+}
+
+impl<I> LimitPolicy<I> for NoLimit {
+    fn settings(&self) -> Option<&Aimd> {
+        None
+    }
+    fn track(&self, _item: &mut I, _completion: Completion) {
+        unreachable!("requests are never tracked");
+    }
+}
+
+impl<I: Tracked> LimitPolicy<I> for Aimd {
+    fn settings(&self) -> Option<&Aimd> {
+        Some(self)
+    }
+    fn track(&self, item: &mut I, completion: Completion) {
+        item.track(completion)
+    }
+}
+
+impl Completion {
+    /// Request is completed successfully
+    pub fn success(self) {
+        let overloaded = match self.host.settings.latency {
+            Some(latency) => self.started.elapsed() > latency,
+            None => false,
+        };
+        self.complete(Some(!overloaded));
+    }
+    /// Request is failed, e.g. connection is closed or timed out
+    pub fn failure(self) {
+        self.complete(Some(false));
+    }
+    fn complete(&self, success: Option<bool>) {
+        if !self.done.swap(true, Ordering::SeqCst) {
+            self.host.complete(success);
+        }
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        self.complete(None);
+    }
+}
+
+impl Slot {
+    /// Request is accepted by connection, only completion frees the slot
+    pub fn accepted(mut self) {
+        self.accepted = true;
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.accepted && !self.done.swap(true, Ordering::SeqCst) {
+            self.host.complete(None);
+        }
+    }
+}
+
+impl fmt::Debug for Completion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Completion")
+            .field("started", &self.started)
+            .finish()
+    }
+}
+
+impl Host {
+    pub fn new(settings: &Aimd, task: &Arc<AtomicWaker>) -> Host {
+        Host {
+            state: Mutex::new(State {
+                limit: settings.initial as f64,
+                in_flight: 0,
+                changed: true,
+                freed: false,
+            }),
+            settings: settings.clone(),
+            task: task.clone(),
+        }
+    }
+    /// Takes a slot for a request, returns `None` if host is at its limit
+    pub fn acquire(host: &Arc<Host>) -> Option<(Completion, Slot)> {
+        let mut state = host.state.lock().expect("limit is not poisoned");
+        if state.in_flight as f64 >= state.limit.floor() {
+            return None;
+        }
+        state.in_flight += 1;
+        let done = Arc::new(AtomicBool::new(false));
+        Some((Completion {
+            host: host.clone(),
+            started: Instant::now(),
+            done: done.clone(),
+        }, Slot {
+            host: host.clone(),
+            done,
+            accepted: false,
+        }))
+    }
+    pub fn is_saturated(&self) -> bool {
+        let state = self.state.lock().expect("limit is not poisoned");
+        state.in_flight as f64 >= state.limit.floor()
+    }
+    /// Returns current limit if it's changed since the last call
+    pub fn take_changed(&self) -> Option<usize> {
+        let mut state = self.state.lock().expect("limit is not poisoned");
+        if state.changed {
+            state.changed = false;
+            Some(state.limit as usize)
+        } else {
+            None
+        }
+    }
+    /// Returns true if some slot is freed since the last call
+    pub fn take_freed(&self) -> bool {
+        let mut state = self.state.lock().expect("limit is not poisoned");
+        mem::replace(&mut state.freed, false)
+    }
+    fn complete(&self, success: Option<bool>) {
+        {
+            let mut state = self.state.lock().expect("limit is not poisoned");
+            let old = state.limit as usize;
+            state.in_flight -= 1;
+            state.freed = true;
+            match success {
+                Some(true) => {
+                    state.limit = (state.limit + 1. / state.limit)
+                        .min(self.settings.max as f64);
+                }
+                Some(false) => {
+                    state.limit = (state.limit * self.settings.backoff)
+                        .max(self.settings.min as f64);
+                }
+                None => {}
+            }
+            if state.limit as usize != old {
+                state.changed = true;
+            }
+        }
+        self.task.wake();
+    }
+}
+
+impl fmt::Debug for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().expect("limit is not poisoned");
+        f.debug_struct("Host")
+            .field("limit", &state.limit)
+            .field("in_flight", &state.in_flight)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use futures::task::AtomicWaker;
+    use super::{Aimd, Host};
+
+    #[test]
+    fn aimd() {
+        let task = Arc::new(AtomicWaker::new());
+        let host = Arc::new(Host::new(
+            &Aimd::new().initial_limit(2).limits(1, 3), &task));
+        assert_eq!(host.take_changed(), Some(2));
+        let (a, slot) = Host::acquire(&host).unwrap();
+        slot.accepted();
+        let (b, slot) = Host::acquire(&host).unwrap();
+        slot.accepted();
+        assert!(Host::acquire(&host).is_none());
+        a.success();
+        b.success();
+        assert_eq!(host.take_changed(), None);
+        Host::acquire(&host).unwrap().0.success();
+        assert_eq!(host.take_changed(), Some(3));
+        Host::acquire(&host).unwrap().0.failure();
+        assert_eq!(host.take_changed(), Some(2));
+        // dropped completion doesn't change the limit
+        drop(Host::acquire(&host).unwrap());
+        assert_eq!(host.take_changed(), None);
+        // completion reports only once, slot is already freed
+        let (c, slot) = Host::acquire(&host).unwrap();
+        drop(slot);
+        c.failure();
+        assert_eq!(host.take_changed(), None);
+        assert!(!host.is_saturated());
+    }
+}
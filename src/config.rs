@@ -3,22 +3,41 @@
 //! Usually you should start with ``pool_for`` and use methods to configure
 //! connection pool instead of poking at these types.
 //!
+use std::fmt;
+use std::hash::Hash;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
 use std::time::Duration;
 
 use abstract_ns::Address;
-use futures::{Future, Stream, Sink};
-use tokio_core::reactor::Handle;
-use void::Void;
+use futures::{Stream, Sink};
+use tokio::runtime::Handle;
 
+use address::{AddressSet, PoolAddress, StaticAddress};
+use concurrency::{Aimd, NoLimit};
 use error_log::{ErrorLog, WarnLogger};
+use fair;
+use fail_fast::{FailFast, Wait};
 use connect::Connect;
+use events::Subscribers;
+use initialize::{Initialize, Initialized};
 use metrics::{self, Collect};
-use uniform::LazyUniform;
+use queue::{Overflow, Discipline};
+use rate_limit::{RateLimit, Throttle};
+use retry::{NoRetry, Retry};
+use route::{NoRouting, Routing};
+use settings::{self, Settings};
+use shutdown::{Shutdown, ShutdownHandle};
+use timer::{Timer, SharedTimer};
+use uniform::{self, LazyUniform};
 
 /// A constructor for metrics collector object used for connection pool
 pub trait NewMetrics {
     type Collect: Collect;
-    fn construct(self) -> Self::Collect;
+    /// Create a metrics collector for the pool with the specified name
+    fn construct(self, name: &str) -> Self::Collect;
 }
 
 /// A constructor for queue
@@ -35,67 +54,157 @@ impl<I, M, T: private::NewQueue<I, M>> NewQueue<I, M> for T {
 
 /// A constructor for multiplexer
 ///
+/// First type parameter is the type of requests sent to the pool.
+///
 /// This trait is currently *sealed*, we will unseal it once it stabilized
-pub trait NewMux<A, C, E, M>: private::NewMux<A, C, E, M>
-    where A: Stream<Item=Address, Error=Void>,
+pub trait NewMux<I, A, C, E, M>: private::NewMux<I, A, C, E, M>
+    where A: Stream,
+          A::Item: AddressSet<Addr=<C as Connect>::Address>,
           C: Connect + 'static,
-          <<C as Connect>::Future as Future>::Item: Sink,
-          E: ErrorLog<
-            ConnectionError=<C::Future as Future>::Error,
-            SinkError=<<C::Future as Future>::Item as Sink>::SinkError,
+          C::Connection: Sink<I>,
+          E: ErrorLog<<C as Connect>::Address,
+            ConnectionError=C::Error,
+            SinkError=<C::Connection as Sink<I>>::Error,
             >,
           E: 'static,
           M: Collect + 'static,
 {}
 
 pub(crate) mod private {
-    use futures::{Stream, Future, Sink};
-    use void::Void;
+    use std::task::{Context, Poll};
+    use futures::{Stream, Sink};
     use connect::Connect;
     use metrics::Collect;
     use error_log::ErrorLog;
-    use abstract_ns::Address;
-    use tokio_core::reactor::Handle;
+    use events::Subscribers;
+    use address::AddressSet;
+    use shutdown::Shutdown;
+    use timer::SharedTimer;
 
     pub struct Done;
 
-    pub trait NewMux<A, C, E, M>
-        where A: Stream<Item=Address, Error=Void>,
+    /// Result of ``Mux::start_send``
+    #[derive(Debug, PartialEq)]
+    pub enum AsyncSink<T> {
+        Ready,
+        /// Item can't be sent right now and is returned back
+        NotReady(T),
+    }
+
+    /// Multiplexer which dispatches requests to connections
+    ///
+    /// Unlike futures `Sink` it doesn't reserve a slot for an item in
+    /// advance, as connection request is sent to depends on the request
+    /// itself. Current task is woken up when the item might be sent again.
+    pub trait Mux<I> {
+        fn start_send(&mut self, cx: &mut Context, item: I)
+            -> Result<AsyncSink<I>, Done>;
+        fn poll_complete(&mut self, cx: &mut Context)
+            -> Poll<Result<(), Done>>;
+        fn close(&mut self, cx: &mut Context) -> Poll<Result<(), Done>>;
+    }
+
+    pub trait RetryPolicy<I> {
+        fn settings(&self) -> Option<&::retry::Retry>;
+        fn copy(&self, item: &I) -> Option<I>;
+    }
+
+    pub trait DropCallback<I> {
+        fn into_callback(self)
+            -> Option<::std::sync::Arc<dyn Fn(I) + Send + Sync>>;
+    }
+
+    pub trait TenantOf<I> {
+        fn into_extractor(self) -> ::fair::Extractor<I>;
+    }
+
+    pub trait LimitPolicy<I> {
+        fn settings(&self) -> Option<&::concurrency::Aimd>;
+        fn track(&self, item: &mut I,
+            completion: ::concurrency::Completion);
+    }
+
+    /// Multiplexer which can be configured by ``Settings``
+    pub trait ConfigureMux {
+        type Mux;
+        fn configure(self, config: ::uniform::Config) -> Self::Mux;
+    }
+
+    /// Queue which can be resized keeping other settings
+    pub trait ResizeQueue {
+        type Queue;
+        fn resize(self, size: usize, overflow: ::queue::Overflow)
+            -> Self::Queue;
+    }
+
+    pub trait FailPolicy<I> {
+        fn timeout(&self) -> Option<::std::time::Duration>;
+        fn reject(&self, item: I);
+    }
+
+    pub trait RoutePolicy<I, P> {
+        fn enabled(&self) -> bool;
+        fn pinned(&self, item: &I) -> Option<P>;
+        fn is_excluded(&self, item: &I, addr: &P) -> bool;
+        fn dispatched(&self, item: &mut I, addr: &P);
+    }
+
+    pub trait NewMux<I, A, C, E, M>
+        where A: Stream,
+              A::Item: AddressSet<Addr=<C as Connect>::Address>,
               C: Connect + 'static,
-              <<C as Connect>::Future as Future>::Item: Sink,
-              E: ErrorLog<
-                ConnectionError=<C::Future as Future>::Error,
-                SinkError=<<C::Future as Future>::Item as Sink>::SinkError,
+              C::Connection: Sink<I>,
+              E: ErrorLog<<C as Connect>::Address,
+                ConnectionError=C::Error,
+                SinkError=<C::Connection as Sink<I>>::Error,
                 >,
               E: 'static,
               M: Collect + 'static,
     {
-        type Sink: Sink<
-            SinkItem=<<C::Future as Future>::Item as Sink>::SinkItem,
-            SinkError=Done,
-        >;
+        type Sink: Mux<I>;
         fn construct(self,
-            h: &Handle, address: A, connector: C, errors: E, metrics: M)
+            timer: &SharedTimer, address: A, connector: C, errors: E,
+            metrics: M, events: Subscribers)
             -> Self::Sink;
     }
 
     pub trait NewQueue<I, M> {
         type Pool;
-        fn spawn_on<S, E>(self, pool: S, e: E, metrics: M, handle: &Handle)
-            -> Self::Pool
-            where S: Sink<SinkItem=I, SinkError=Done> + 'static,
-                  E: ErrorLog + 'static,
+        fn build<S, E, P>(self, pool: S, e: E, metrics: M,
+            events: Subscribers, shutdown: Shutdown)
+            -> (Self::Pool, super::PoolFuture)
+            where S: Mux<I> + Send + 'static,
+                  E: ErrorLog<P> + Send + 'static,
+                  P: 'static,
                   M: Collect + 'static;
     }
 
 }
 
 /// A constructor for error log
-pub trait NewErrorLog<C, S> {
-    type ErrorLog: ErrorLog<ConnectionError=C, SinkError=S>;
-    fn construct(self) -> Self::ErrorLog;
+///
+/// Last type parameter is the address type of the pool.
+pub trait NewErrorLog<C, S, P=SocketAddr> {
+    type ErrorLog: ErrorLog<P, ConnectionError=C, SinkError=S>;
+    /// Create an error log for the pool with the specified name
+    fn construct(self, name: &str) -> Self::ErrorLog;
 }
 
+/// A future that drives connection pool returned from ``PoolConfig::build``
+pub type PoolFuture = Pin<Box<dyn Future<Output=()> + Send>>;
+
+/// Type of the pool returned by ``PoolConfig::build``
+pub(crate) type PoolOf<I, Q, M> = <Q as NewQueue<
+    I,
+    <M as NewMetrics>::Collect,
+>>::Pool;
+
+/// Type of the config returned by ``PoolConfig::apply_settings``
+type Configured<C, A, X, Q, E, M> = PoolConfig<C, A,
+    <X as private::ConfigureMux>::Mux,
+    <Q as private::ResizeQueue>::Queue,
+    E, M>;
+
 /// A configuration builder that holds onto `Connect` object
 #[derive(Debug)]
 pub struct PartialConfig<C> {
@@ -104,84 +213,344 @@ pub struct PartialConfig<C> {
 
 /// A fully configured pool but you might override some defaults
 pub struct PoolConfig<C, A, X, Q, E, M> {
+    pub(crate) name: String,
     pub(crate) connector: C,
     pub(crate) address: A,
     pub(crate) mux: X,
     pub(crate) queue: Q,
     pub(crate) errors: E,
     pub(crate) metrics: M,
+    pub(crate) shutdown: ShutdownHandle,
+    pub(crate) rate_limit: Option<RateLimit>,
 }
 
 /// A constructor for a default multiplexer
+#[derive(Debug, Clone)]
 pub struct DefaultMux;
 
 /// A constructor for a default queue
+#[derive(Debug, Clone)]
 pub struct DefaultQueue;
 
 /// A constructor for a fixed-size dumb queue
-pub struct Queue(pub(crate) usize);
+///
+/// Type parameter is a callback for requests dropped due to overflow (see
+/// ``PoolConfig::on_queue_drop``).
+#[derive(Clone)]
+pub struct Queue<D=NoDrop> {
+    pub(crate) size: usize,
+    pub(crate) overflow: Overflow,
+    pub(crate) discipline: Discipline,
+    pub(crate) on_drop: D,
+}
+
+/// A constructor for a queue which is fair across tenants
+///
+/// Type parameter is a function which returns tenant of a request (see
+/// ``PoolConfig::with_fair_queue``).
+#[derive(Clone)]
+pub struct FairQueue<K> {
+    pub(crate) capacity: usize,
+    pub(crate) quantum: usize,
+    pub(crate) tenant: K,
+}
+
+/// No callback for requests dropped due to queue overflow (default)
+#[derive(Debug, Clone)]
+pub struct NoDrop;
 
 /// A constructor for a default (no-op) metrics collector
+#[derive(Debug, Clone)]
 pub struct NoopMetrics;
 
 impl NewMetrics for NoopMetrics {
     type Collect = metrics::Noop;
-    fn construct(self) -> metrics::Noop {
+    fn construct(self, _name: &str) -> metrics::Noop {
         metrics::Noop
     }
 }
 
+/// Cloned config creates a separate pool with its own ``ShutdownHandle``
+impl<C, A, X, Q, E, M> Clone for PoolConfig<C, A, X, Q, E, M>
+    where C: Clone, A: Clone, X: Clone, Q: Clone, E: Clone, M: Clone,
+{
+    fn clone(&self) -> Self {
+        PoolConfig {
+            name: self.name.clone(),
+            connector: self.connector.clone(),
+            address: self.address.clone(),
+            mux: self.mux.clone(),
+            queue: self.queue.clone(),
+            errors: self.errors.clone(),
+            metrics: self.metrics.clone(),
+            shutdown: ShutdownHandle::new(),
+            rate_limit: self.rate_limit.clone(),
+        }
+    }
+}
+
+impl Queue {
+    pub(crate) fn new(size: usize) -> Queue {
+        Queue {
+            size,
+            overflow: Overflow::Backpressure,
+            discipline: Discipline::Fifo,
+            on_drop: NoDrop,
+        }
+    }
+}
+
+impl private::ConfigureMux for DefaultMux {
+    type Mux = LazyUniform;
+    fn configure(self, config: uniform::Config) -> LazyUniform {
+        LazyUniform {
+            config,
+            updates: None,
+            retry: NoRetry,
+            route: NoRouting,
+            fail: Wait,
+            limit: NoLimit,
+        }
+    }
+}
+
+impl<R, H, F, L> private::ConfigureMux for LazyUniform<R, H, F, L> {
+    type Mux = LazyUniform<R, H, F, L>;
+    fn configure(self, config: uniform::Config) -> Self::Mux {
+        LazyUniform { config, ..self }
+    }
+}
+
+impl private::ResizeQueue for DefaultQueue {
+    type Queue = Queue;
+    fn resize(self, size: usize, overflow: Overflow) -> Queue {
+        Queue { overflow, ..Queue::new(size) }
+    }
+}
+
+impl<D> private::ResizeQueue for Queue<D> {
+    type Queue = Queue<D>;
+    fn resize(self, size: usize, overflow: Overflow) -> Queue<D> {
+        Queue { size, overflow, ..self }
+    }
+}
+
+impl<I> private::DropCallback<I> for NoDrop {
+    fn into_callback(self) -> Option<Arc<dyn Fn(I) + Send + Sync>> {
+        None
+    }
+}
+
+impl<I, F> private::DropCallback<I> for F
+    where F: Fn(I) + Send + Sync + 'static,
+{
+    fn into_callback(self) -> Option<Arc<dyn Fn(I) + Send + Sync>> {
+        Some(Arc::new(self))
+    }
+}
+
+impl<I, K, T> private::TenantOf<I> for K
+    where K: Fn(&I) -> T + Send + Sync + 'static,
+          T: Hash + Eq + fmt::Display + Send + Sync + 'static,
+{
+    fn into_extractor(self) -> fair::Extractor<I> {
+        Arc::new(move |item| fair::Tenant::new(self(item)))
+    }
+}
+
 impl<C> PartialConfig<C> {
+    /// Initialize every established connection before using it
+    ///
+    /// Initializer is called with the address and connection returned by
+    /// the connector, and returns a future which resolves to the
+    /// connection used by the pool. Errors of either stage are reported as
+    /// ``initialize::InitError``, initialization errors don't blacklist
+    /// the address.
+    pub fn initialize<I>(self, initializer: I)
+        -> PartialConfig<Initialized<C, I>>
+        where C: Connect,
+              I: Initialize<C::Address, C::Connection> + Clone,
+    {
+        PartialConfig {
+            connector: Initialized::new(self.connector, initializer),
+        }
+    }
+
     /// Create a configuration by adding an address stream
     pub fn connect_to<A>(self, address_stream: A)
         -> PoolConfig<C, A, DefaultMux, DefaultQueue, WarnLogger, NoopMetrics>
-        where A: Stream<Item=Address, Error=Void>,
+        where A: Stream,
+              A::Item: AddressSet<Addr=<C as Connect>::Address>,
+              C: Connect,
     {
         PoolConfig {
+            name: String::from("unnamed"),
             address: address_stream,
             connector: self.connector,
             mux: DefaultMux,
-            errors: WarnLogger,
+            errors: WarnLogger::new(),
             queue: DefaultQueue,
             metrics: NoopMetrics,
+            shutdown: ShutdownHandle::new(),
+            rate_limit: None,
         }
     }
+
+    /// Create a configuration with a fixed list of addresses
+    ///
+    /// Address stream never ends, so pool works until all ``queue::Pool``
+    /// handles are dropped or it's shut down using ``ShutdownHandle``.
+    pub fn connect_to_static<P>(self, addresses: &[P])
+        -> PoolConfig<C, StaticAddress<Vec<P>>,
+                      DefaultMux, DefaultQueue, WarnLogger, NoopMetrics>
+        where P: PoolAddress,
+              C: Connect<Address=P>,
+    {
+        self.connect_to(StaticAddress::new(addresses.to_vec()))
+    }
+
+    /// Create a configuration with a fixed name service address
+    ///
+    /// Works like ``connect_to_static`` but accepts already resolved
+    /// ``abstract_ns::Address``.
+    pub fn connect_to_addr(self, address: Address)
+        -> PoolConfig<C, StaticAddress<Address>,
+                      DefaultMux, DefaultQueue, WarnLogger, NoopMetrics>
+        where C: Connect<Address=SocketAddr>,
+    {
+        self.connect_to(StaticAddress::new(address))
+    }
+}
+
+impl<C, I> PartialConfig<Initialized<C, I>> {
+    /// Set a time limit of connection initialization
+    ///
+    /// Connection is closed if it isn't initialized in time, this is
+    /// reported as ``initialize::InitError::Timeout``. There is no limit
+    /// by default.
+    pub fn initialize_timeout(mut self, timeout: Duration) -> Self {
+        self.connector.set_timeout(timeout);
+        self
+    }
 }
 
 impl<C, A, X, Q, E, M> PoolConfig<C, A, X, Q, E, M> {
-    /// Spawn a connection pool on the main loop specified by handle
-    pub fn spawn_on(self, h: &Handle)
-        -> <Q as NewQueue<
-                <<<C as Connect>::Future as Future>::Item as Sink>::SinkItem,
-                <M as NewMetrics>::Collect,
-           >>::Pool
-        where A: Stream<Item=Address, Error=Void>,
+    /// Spawn a connection pool on the runtime specified by handle
+    ///
+    /// Type parameter is the type of requests sent to the pool, it's
+    /// usually inferred from the connection `Sink`. Returns the pool and a
+    /// handle which might be used to shut it down.
+    pub fn spawn_on<I>(self, h: &Handle)
+        -> (PoolOf<I, Q, M>, ShutdownHandle)
+        where A: Stream + Send + 'static,
+              A::Item: AddressSet<Addr=<C as Connect>::Address>,
               C: Connect + 'static,
-              <<C as Connect>::Future as Future>::Item: Sink,
+              C::Connection: Sink<I>,
               M: NewMetrics,
               M::Collect: 'static,
-              X: NewMux<A, C, E::ErrorLog, M::Collect>,
-              <X as private::NewMux<A, C, E::ErrorLog, M::Collect>>::Sink: 'static,
+              X: NewMux<I, A, C, E::ErrorLog, M::Collect>,
+              <X as private::NewMux<I, A, C, E::ErrorLog, M::Collect>>::Sink:
+                Send + 'static,
               E: NewErrorLog<
-                <<C as Connect>::Future as Future>::Error,
-                <<<C as Connect>::Future as Future>::Item as Sink>::SinkError,
+                C::Error,
+                <C::Connection as Sink<I>>::Error,
+                <C as Connect>::Address,
               >,
-              E::ErrorLog: Clone + 'static,
-              Q: NewQueue<
-                <<<C as Connect>::Future as Future>::Item as Sink>::SinkItem,
-                <M as NewMetrics>::Collect,
+              E::ErrorLog: Clone + Send + 'static,
+              Q: NewQueue<I, <M as NewMetrics>::Collect,
                 Pool=<Q as private::NewQueue<
-                    <<<C as Connect>::Future as Future>::Item as Sink>::SinkItem,
+                    I,
                     <M as NewMetrics>::Collect,
                 >>::Pool
               >,
+    {
+        let shutdown = self.shutdown.clone();
+        let (pool, future) = self.build(h.clone());
+        h.spawn(future);
+        (pool, shutdown)
+    }
 
+    /// Build a connection pool without spawning it
+    ///
+    /// Returns the pool and a future which must be driven by an executor
+    /// for the pool to work. Timer is used for blacklisting failing hosts.
+    /// This allows running pool on any executor, not necessarily the
+    /// `tokio` one.
+    pub fn build<I, T>(self, timer: T)
+        -> (PoolOf<I, Q, M>, PoolFuture)
+        where A: Stream + Send + 'static,
+              A::Item: AddressSet<Addr=<C as Connect>::Address>,
+              C: Connect + 'static,
+              C::Connection: Sink<I>,
+              M: NewMetrics,
+              M::Collect: 'static,
+              X: NewMux<I, A, C, E::ErrorLog, M::Collect>,
+              <X as private::NewMux<I, A, C, E::ErrorLog, M::Collect>>::Sink:
+                Send + 'static,
+              E: NewErrorLog<
+                C::Error,
+                <C::Connection as Sink<I>>::Error,
+                <C as Connect>::Address,
+              >,
+              E::ErrorLog: Clone + Send + 'static,
+              Q: NewQueue<I, <M as NewMetrics>::Collect,
+                Pool=<Q as private::NewQueue<
+                    I,
+                    <M as NewMetrics>::Collect,
+                >>::Pool
+              >,
+              T: Timer + 'static,
+    {
+        let timer: SharedTimer = Arc::new(timer);
+        let m = self.metrics.construct(&self.name);
+        let e = self.errors.construct(&self.name);
+        let ev = Subscribers::new();
+        let shutdown = Shutdown::new(&self.shutdown, &timer);
+        let p = self.mux.construct(&timer,
+            self.address, self.connector, e.clone(), m.clone(), ev.clone());
+        let p = Throttle::new(p, self.rate_limit, &timer, m.clone());
+        self.queue.build(p, e, m, ev, shutdown)
+    }
+
+    /// Returns a handle to shut down the pool
+    ///
+    /// Useful with ``PoolConfig::build``, ``spawn_on`` returns the same
+    /// handle.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Set the name of the connection pool
+    ///
+    /// The name is passed to the error log and metrics constructors, so
+    /// that messages and metrics of different pools can be told apart.
+    /// Default name is `unnamed`.
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Replace the address stream
+    ///
+    /// Useful to create pools for different services from a single
+    /// template config (see ``registry::PoolRegistry``).
+    pub fn connect_to<B>(self, address_stream: B)
+        -> PoolConfig<C, B, X, Q, E, M>
+        where B: Stream,
+              B::Item: AddressSet<Addr=<C as Connect>::Address>,
+              C: Connect,
     {
-        let m = self.metrics.construct();
-        let e = self.errors.construct();
-        let p = self.mux.construct(h,
-            self.address, self.connector, e.clone(), m.clone());
-        self.queue.spawn_on(p, e, m, h)
+        PoolConfig {
+            name: self.name,
+            address: address_stream,
+            connector: self.connector,
+            mux: self.mux,
+            queue: self.queue,
+            errors: self.errors,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
     }
 
     /// Configure a uniform connection pool with specified number of
@@ -190,29 +559,134 @@ impl<C, A, X, Q, E, M> PoolConfig<C, A, X, Q, E, M> {
         -> PoolConfig<C, A, LazyUniform, Q, E, M>
     {
         PoolConfig {
+            name: self.name,
             mux: LazyUniform {
-                conn_limit: num,
-                reconnect_timeout: Duration::from_millis(100),
+                config: uniform::Config::new(num),
+                updates: None,
+                retry: NoRetry,
+                route: NoRouting,
+                fail: Wait,
+                limit: NoLimit,
             },
             address: self.address,
             connector: self.connector,
             errors: self.errors,
             queue: self.queue,
             metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
         }
     }
 
+    /// Configure multiplexer and queue using plain data settings
+    ///
+    /// This replaces connection limit, timeouts, queue size and overflow
+    /// policy. Policies configured so far (retry, routing, fail-fast,
+    /// concurrency limit, queue discipline and drop callback) and
+    /// ``config_stream`` are kept. Settings can't be applied to a fair
+    /// queue.
+    pub fn apply_settings(self, settings: &Settings)
+        -> Result<Configured<C, A, X, Q, E, M>, settings::Error>
+        where X: private::ConfigureMux,
+              Q: private::ResizeQueue,
+    {
+        let config = settings.uniform_config()?;
+        Ok(PoolConfig {
+            name: self.name,
+            mux: self.mux.configure(config),
+            queue: self.queue.resize(settings.queue_size,
+                settings.queue_overflow),
+            address: self.address,
+            connector: self.connector,
+            errors: self.errors,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        })
+    }
+
     /// Add a queue of size num used when no connection can accept a message
+    ///
+    /// When queue is full, sender waits (see ``with_queue`` for other
+    /// options).
     pub fn with_queue_size(self, num: usize)
-        -> PoolConfig<C, A, X, Queue, E, M>
+        -> PoolConfig<C, A, X, <Q as private::ResizeQueue>::Queue, E, M>
+        where Q: private::ResizeQueue,
+    {
+        self.with_queue(num, Overflow::Backpressure)
+    }
+
+    /// Add a queue of size num with the specified overflow policy
+    ///
+    /// Requests dropped due to overflow are counted by
+    /// ``Collect::request_dropped`` and passed to the callback set by
+    /// ``on_queue_drop``. If the queue is already configured, its
+    /// discipline and drop callback are kept.
+    pub fn with_queue(self, num: usize, overflow: Overflow)
+        -> PoolConfig<C, A, X, <Q as private::ResizeQueue>::Queue, E, M>
+        where Q: private::ResizeQueue,
     {
         PoolConfig {
-            queue: Queue(num),
+            name: self.name,
+            queue: self.queue.resize(num, overflow),
             address: self.address,
             connector: self.connector,
             mux: self.mux,
             errors: self.errors,
             metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    /// Add a queue which is fair across tenants
+    ///
+    /// Every tenant, as returned by `tenant` function for a request, has
+    /// its own queue of size `capacity_per_tenant`. When the queue is full,
+    /// sender waits, so a noisy tenant can't starve the others. Queued
+    /// requests are forwarded using deficit round-robin: every tenant
+    /// sends up to one request per round (see ``fair_queue_quantum``).
+    ///
+    /// Depth of every tenant's queue is reported with
+    /// ``Collect::tenant_queue_depth``.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity_per_tenant` is zero.
+    pub fn with_fair_queue<I, K, T>(self, capacity_per_tenant: usize,
+        tenant: K)
+        -> PoolConfig<C, A, X, FairQueue<K>, E, M>
+        where K: Fn(&I) -> T + Send + Sync + 'static,
+              T: Hash + Eq + fmt::Display + Send + Sync + 'static,
+    {
+        assert!(capacity_per_tenant > 0, "queue capacity must be positive");
+        PoolConfig {
+            name: self.name,
+            queue: FairQueue {
+                capacity: capacity_per_tenant,
+                quantum: 1,
+                tenant,
+            },
+            address: self.address,
+            connector: self.connector,
+            mux: self.mux,
+            errors: self.errors,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    /// Limit rate of requests forwarded to backends
+    ///
+    /// The limit is pool-wide, requests wait in the queue until they are
+    /// allowed to be sent. See ``rate_limit`` module for details.
+    pub fn rate_limit(self, limit: RateLimit)
+        -> PoolConfig<C, A, X, Q, E, M>
+    {
+        PoolConfig {
+            rate_limit: Some(limit),
+            .. self
         }
     }
 
@@ -222,29 +696,245 @@ impl<C, A, X, Q, E, M> PoolConfig<C, A, X, Q, E, M> {
         where NM: NewMetrics,
     {
         PoolConfig {
+            name: self.name,
             queue: self.queue,
             address: self.address,
             connector: self.connector,
             mux: self.mux,
             errors: self.errors,
             metrics: metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
         }
     }
 
     /// Override error reporter
+    ///
+    /// This accepts a constructor of the error log (``NewErrorLog``), the
+    /// error log itself is created when pool is spawned. Use ``WarnLogger``
+    /// if you only want to change log levels or target.
     pub fn errors<NE>(self, errors: NE)
         -> PoolConfig<C, A, X, Q, NE, M>
-        where NE: ErrorLog,
+        where C: Connect,
     {
         PoolConfig {
+            name: self.name,
             queue: self.queue,
             address: self.address,
             connector: self.connector,
             mux: self.mux,
             errors: errors,
             metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+}
+
+impl<C, A, X, D, E, M> PoolConfig<C, A, X, Queue<D>, E, M> {
+    /// Set a callback for requests dropped due to queue overflow
+    ///
+    /// Callback is called on the thread which sends a request to the pool,
+    /// or on the pool's thread for requests dropped by
+    /// ``Discipline::Codel``.
+    pub fn on_queue_drop<I, F>(self, callback: F)
+        -> PoolConfig<C, A, X, Queue<F>, E, M>
+        where F: Fn(I) + Send + Sync + 'static,
+    {
+        PoolConfig {
+            name: self.name,
+            queue: Queue {
+                size: self.queue.size,
+                overflow: self.queue.overflow,
+                discipline: self.queue.discipline,
+                on_drop: callback,
+            },
+            address: self.address,
+            connector: self.connector,
+            mux: self.mux,
+            errors: self.errors,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    /// Set the order in which queued requests are forwarded
+    ///
+    /// By default requests are forwarded in FIFO order. See ``Discipline``
+    /// for options that keep latency low when the pool is overloaded.
+    pub fn queue_discipline(self, discipline: Discipline)
+        -> PoolConfig<C, A, X, Queue<D>, E, M>
+    {
+        PoolConfig {
+            name: self.name,
+            queue: Queue {
+                discipline,
+                .. self.queue
+            },
+            address: self.address,
+            connector: self.connector,
+            mux: self.mux,
+            errors: self.errors,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+}
+
+impl<C, A, X, K, E, M> PoolConfig<C, A, X, FairQueue<K>, E, M> {
+    /// Set number of requests every tenant sends per round (default 1)
+    ///
+    /// Larger values make bursts of a single tenant's requests more
+    /// likely to be forwarded together.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `quantum` is zero.
+    pub fn fair_queue_quantum(self, quantum: usize)
+        -> PoolConfig<C, A, X, FairQueue<K>, E, M>
+    {
+        assert!(quantum > 0, "quantum must be positive");
+        PoolConfig {
+            name: self.name,
+            queue: FairQueue {
+                quantum,
+                .. self.queue
+            },
+            address: self.address,
+            connector: self.connector,
+            mux: self.mux,
+            errors: self.errors,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
         }
     }
 }
 
+impl<C, A, R, H, F, L, Q, E, M>
+    PoolConfig<C, A, LazyUniform<R, H, F, L>, Q, E, M>
+{
+    /// Reconfigure uniform connection pool at runtime
+    ///
+    /// Every value received from the stream replaces connection limit and
+    /// timeouts of the pool. When connection limit is decreased,
+    /// excessive connections are closed (in-flight requests are still
+    /// processed), when it's increased new connections are established
+    /// lazily as usual. Pool is not affected when stream ends.
+    pub fn config_stream<S>(mut self, stream: S) -> Self
+        where S: Stream<Item=uniform::Config> + Send + 'static,
+    {
+        self.mux.updates = Some(Box::pin(stream));
+        self
+    }
 
+    /// Retry requests lost on broken connections
+    ///
+    /// Requests must implement ``Retryable``, see ``retry`` module for
+    /// details.
+    pub fn retry(self, policy: Retry)
+        -> PoolConfig<C, A, LazyUniform<Retry, H, F, L>, Q, E, M>
+    {
+        PoolConfig {
+            name: self.name,
+            mux: LazyUniform {
+                config: self.mux.config,
+                updates: self.mux.updates,
+                retry: policy,
+                route: self.mux.route,
+                fail: self.mux.fail,
+                limit: self.mux.limit,
+            },
+            address: self.address,
+            connector: self.connector,
+            errors: self.errors,
+            queue: self.queue,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    /// Honour routing hints of requests
+    ///
+    /// Requests must implement ``Route``, see ``route`` module for
+    /// details.
+    pub fn routing(self)
+        -> PoolConfig<C, A, LazyUniform<R, Routing, F, L>, Q, E, M>
+    {
+        PoolConfig {
+            name: self.name,
+            mux: LazyUniform {
+                config: self.mux.config,
+                updates: self.mux.updates,
+                retry: self.mux.retry,
+                route: Routing,
+                fail: self.mux.fail,
+                limit: self.mux.limit,
+            },
+            address: self.address,
+            connector: self.connector,
+            errors: self.errors,
+            queue: self.queue,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    /// Fail requests when there are no healthy backends
+    ///
+    /// Requests must implement ``Rejectable``, see ``fail_fast`` module
+    /// for details.
+    pub fn fail_fast(self, policy: FailFast)
+        -> PoolConfig<C, A, LazyUniform<R, H, FailFast, L>, Q, E, M>
+    {
+        PoolConfig {
+            name: self.name,
+            mux: LazyUniform {
+                config: self.mux.config,
+                updates: self.mux.updates,
+                retry: self.mux.retry,
+                route: self.mux.route,
+                fail: policy,
+                limit: self.mux.limit,
+            },
+            address: self.address,
+            connector: self.connector,
+            errors: self.errors,
+            queue: self.queue,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    /// Limit number of outstanding requests per host adaptively
+    ///
+    /// Requests must implement ``Tracked``, see ``concurrency`` module
+    /// for details.
+    pub fn adaptive_concurrency(self, limit: Aimd)
+        -> PoolConfig<C, A, LazyUniform<R, H, F, Aimd>, Q, E, M>
+    {
+        PoolConfig {
+            name: self.name,
+            mux: LazyUniform {
+                config: self.mux.config,
+                updates: self.mux.updates,
+                retry: self.mux.retry,
+                route: self.mux.route,
+                fail: self.mux.fail,
+                limit,
+            },
+            address: self.address,
+            connector: self.connector,
+            errors: self.errors,
+            queue: self.queue,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            rate_limit: self.rate_limit,
+        }
+    }
+}
@@ -1,24 +1,99 @@
+use std::marker::PhantomData;
 use std::net::SocketAddr;
+use std::future::Future;
+use std::sync::Arc;
 
-use futures::{Future, IntoFuture};
+use address::PoolAddress;
+use timer::Timer;
 
 
 /// This is a trait that is used for establishing a connection
 ///
 /// Usually just passing a closure is good enough
 pub trait Connect {
+    /// Type of address connector accepts
+    ///
+    /// Closures accept `SocketAddr`, use ``connect_fn`` to create
+    /// a connector for other address types.
+    type Address: PoolAddress;
+    /// Connection established by the connector (usually a `Sink`)
+    type Connection;
+    /// Error of establishing a connection
+    type Error;
     /// A future retuned by `connect` method
-    type Future: Future;
+    type Future: Future<Output=Result<Self::Connection, Self::Error>>;
     /// Establish a connection to the specified address
-    fn connect(&mut self, address: SocketAddr) -> Self::Future;
+    fn connect(&mut self, address: Self::Address) -> Self::Future;
+    /// Establish a connection, timeouts may use the timer of the pool
+    ///
+    /// This is what connection pool calls, by default it's the same as
+    /// ``connect``.
+    fn connect_with_timer(&mut self, address: Self::Address,
+        _timer: &Arc<dyn Timer>)
+        -> Self::Future
+    {
+        self.connect(address)
+    }
+    /// Returns true if error happened when initializing a connection
+    ///
+    /// Such errors don't blacklist the address (see ``initialize``).
+    fn is_initialize_error(&self, _error: &Self::Error) -> bool {
+        false
+    }
+}
+
+/// A connector for arbitrary address type created from a function
+///
+/// Create it with ``connect_fn``.
+pub struct ConnectFn<F, P> {
+    function: F,
+    phantom: PhantomData<fn(P)>,
+}
+
+/// Create a connector from a function accepting any ``PoolAddress``
+pub fn connect_fn<F, P, R, S, E>(function: F) -> ConnectFn<F, P>
+    where F: FnMut(P) -> R,
+          P: PoolAddress,
+          R: Future<Output=Result<S, E>>,
+{
+    ConnectFn {
+        function,
+        phantom: PhantomData,
+    }
+}
+
+impl<F: Clone, P> Clone for ConnectFn<F, P> {
+    fn clone(&self) -> Self {
+        ConnectFn {
+            function: self.function.clone(),
+            phantom: PhantomData,
+        }
+    }
 }
 
-impl<T, F> Connect for T
+impl<T, F, S, E> Connect for T
     where T: FnMut(SocketAddr) -> F,
-          F: IntoFuture,
+          F: Future<Output=Result<S, E>>,
+{
+    type Address = SocketAddr;
+    type Connection = S;
+    type Error = E;
+    type Future = F;
+    fn connect(&mut self, address: SocketAddr) -> F {
+        (self)(address)
+    }
+}
+
+impl<F, P, R, S, E> Connect for ConnectFn<F, P>
+    where F: FnMut(P) -> R,
+          P: PoolAddress,
+          R: Future<Output=Result<S, E>>,
 {
-    type Future = <T::Output as IntoFuture>::Future;
-    fn connect(&mut self, address: SocketAddr) -> Self::Future {
-        (self)(address).into_future()
+    type Address = P;
+    type Connection = S;
+    type Error = E;
+    type Future = R;
+    fn connect(&mut self, address: P) -> R {
+        (self.function)(address)
     }
 }
@@ -1,15 +1,21 @@
 //! ErrorLog trait and default implementations
 //!
 use std::fmt;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use log::Level;
+
+use address::PoolAddress;
 use config::{NewErrorLog};
 
 /// A reason connection pool being shut down
 ///
 /// This value is passed to ``ErrorLog::pool_shutting_down`` method.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ShutdownReason {
     /// Request stream is shutting down
     ///
@@ -22,6 +28,8 @@ pub enum ShutdownReason {
     /// connection pool, even if there are users. Users will get an error
     /// on the next `start_send`.
     AddressStreamClosed,
+    /// Shutdown is requested using ``ShutdownHandle``
+    Requested,
     #[doc(hidden)]
     __Nonexhaustive,
 }
@@ -32,17 +40,31 @@ pub enum ShutdownReason {
 /// There is a default ``WarnLogger``, but the idea is that you may give
 /// connection pool a name, change logging levels, and do other interesting
 /// stuff in your own error log handler.
-pub trait ErrorLog {
+///
+/// Type parameter is the address type of the pool (see ``PoolAddress``).
+pub trait ErrorLog<P=SocketAddr> {
     /// Connection error type that is returned by connect/hanshake function
+    ///
+    /// With ``PartialConfig::initialize`` it's ``initialize::InitError``
+    /// which tells network errors from initialization errors.
     type ConnectionError;
     /// Error when sending request returned by Sink
     type SinkError;
     /// Error when establishing a new connection
-    fn connection_error(&self, _addr: SocketAddr, _e: Self::ConnectionError) {}
+    fn connection_error(&self, _addr: P, _e: Self::ConnectionError) {}
     /// Error when sending a request
     ///
     /// This also means connection is closed
-    fn sink_error(&self, _addr: SocketAddr, _e: Self::SinkError) {}
+    fn sink_error(&self, _addr: P, _e: Self::SinkError) {}
+    /// There are no healthy backends requests might be sent to
+    ///
+    /// I.e. all addresses are blacklisted. This is called once until some
+    /// request is sent again, and only if pool is configured with
+    /// ``PoolConfig::fail_fast`` (otherwise requests just wait).
+    fn no_healthy_backends(&self) {}
+    /// Request is dropped because it's pinned to an address which is not
+    /// in the pool (see ``route::Route::pinned``)
+    fn unknown_address(&self, _addr: P) {}
     /// Pool is started to shut down for the specified reason
     fn pool_shutting_down(&self, _reason: ShutdownReason) {}
     /// Pool is fully closed at this moment
@@ -51,39 +73,273 @@ pub trait ErrorLog {
 
 
 /// A constructor for a default error logger
-pub struct WarnLogger;
+///
+/// All messages are prefixed by the name of the pool. By default they are
+/// logged at `warn` level with the `tk_pool::error_log` target, both can be
+/// changed for each pool. Level can also be set for each kind of event
+/// separately.
+///
+/// If `rate_limit` is set, an error which is the same as previous one
+/// for the same address is not logged again until the interval passes.
+/// The number of suppressed messages is reported with the next message
+/// logged for the address, or with the next message for any address
+/// after the interval passes.
+#[derive(Debug, Clone)]
+pub struct WarnLogger {
+    target: Option<String>,
+    connection_error: Level,
+    sink_error: Level,
+    no_backends: Level,
+    shutdown: Level,
+    rate_limit: Option<Duration>,
+}
 
 /// An instance of default error logger
-pub struct WarnLoggerInstance<C, S>(PhantomData<* const (C, S)>);
+pub struct WarnLoggerInstance<C, S> {
+    settings: Arc<Settings>,
+    phantom: PhantomData<fn(C, S)>,
+}
+
+#[derive(Debug)]
+struct Settings {
+    name: String,
+    target: String,
+    connection_error: Level,
+    sink_error: Level,
+    no_backends: Level,
+    shutdown: Level,
+    limiter: Option<Mutex<RateLimiter>>,
+}
+
+#[derive(Debug)]
+struct RateLimiter {
+    interval: Duration,
+    last: HashMap<String, Repeated>,
+}
+
+#[derive(Debug)]
+struct Repeated {
+    message: String,
+    logged_at: Instant,
+    suppressed: u32,
+}
+
+impl WarnLogger {
+    /// Create a logger with default settings
+    pub fn new() -> WarnLogger {
+        WarnLogger {
+            target: None,
+            connection_error: Level::Warn,
+            sink_error: Level::Warn,
+            no_backends: Level::Warn,
+            shutdown: Level::Warn,
+            rate_limit: None,
+        }
+    }
+    /// Set the `log` target used for messages of this pool
+    pub fn target<T: Into<String>>(mut self, target: T) -> WarnLogger {
+        self.target = Some(target.into());
+        self
+    }
+    /// Set the level used for all messages of this pool
+    pub fn level(mut self, level: Level) -> WarnLogger {
+        self.connection_error = level;
+        self.sink_error = level;
+        self.no_backends = level;
+        self.shutdown = level;
+        self
+    }
+    /// Set the level used for errors when establishing a connection
+    pub fn connection_error_level(mut self, level: Level) -> WarnLogger {
+        self.connection_error = level;
+        self
+    }
+    /// Set the level used for errors on established connections
+    pub fn sink_error_level(mut self, level: Level) -> WarnLogger {
+        self.sink_error = level;
+        self
+    }
+    /// Set the level used for messages about no healthy backends
+    ///
+    /// This level is also used for requests pinned to an unknown address.
+    pub fn no_backends_level(mut self, level: Level) -> WarnLogger {
+        self.no_backends = level;
+        self
+    }
+    /// Set the level used for messages about pool shutting down
+    pub fn shutdown_level(mut self, level: Level) -> WarnLogger {
+        self.shutdown = level;
+        self
+    }
+    /// Don't repeat the same error for the same address within interval
+    pub fn rate_limit(mut self, interval: Duration) -> WarnLogger {
+        self.rate_limit = Some(interval);
+        self
+    }
+}
+
+impl Default for WarnLogger {
+    fn default() -> WarnLogger {
+        WarnLogger::new()
+    }
+}
+
+impl Settings {
+    fn log_error(&self, level: Level, addr: &str, message: String) {
+        if !log_enabled!(target: &self.target, level) {
+            return;
+        }
+        let suppressed = match self.limiter {
+            Some(ref limiter) => {
+                let mut limiter = limiter.lock()
+                    .expect("error log is not poisoned");
+                let (suppressed, evicted) = limiter.check(addr, &message,
+                    Instant::now());
+                for rep in evicted {
+                    log!(target: &self.target, level,
+                        "[{}] {} ({} similar messages suppressed)",
+                        self.name, rep.message, rep.suppressed);
+                }
+                match suppressed {
+                    Some(suppressed) => suppressed,
+                    None => return,
+                }
+            }
+            None => 0,
+        };
+        if suppressed > 0 {
+            log!(target: &self.target, level,
+                "[{}] {} ({} similar messages suppressed)",
+                self.name, message, suppressed);
+        } else {
+            log!(target: &self.target, level,
+                "[{}] {}", self.name, message);
+        }
+    }
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> RateLimiter {
+        RateLimiter {
+            interval,
+            last: HashMap::new(),
+        }
+    }
+    /// Returns number of suppressed messages if this one should be logged
+    ///
+    /// Also returns expired entries of other addresses which have
+    /// suppressed messages, so their counts are reported as soon as
+    /// anything is logged after the interval passes.
+    fn check(&mut self, addr: &str, message: &str, now: Instant)
+        -> (Option<u32>, Vec<Repeated>)
+    {
+        let evicted = self.evict(addr, now);
+        if let Some(rep) = self.last.get_mut(addr) {
+            if rep.message == message &&
+                now.duration_since(rep.logged_at) < self.interval
+            {
+                rep.suppressed += 1;
+                return (None, evicted);
+            }
+            let suppressed = rep.suppressed;
+            rep.message = message.to_string();
+            rep.logged_at = now;
+            rep.suppressed = 0;
+            return (Some(suppressed), evicted);
+        }
+        self.last.insert(addr.to_string(), Repeated {
+            message: message.to_string(),
+            logged_at: now,
+            suppressed: 0,
+        });
+        (Some(0), evicted)
+    }
+    /// Removes expired entries except `addr`, returns ones which have
+    /// suppressed messages
+    fn evict(&mut self, addr: &str, now: Instant) -> Vec<Repeated> {
+        let interval = self.interval;
+        let expired = self.last.iter()
+            .filter(|&(a, rep)| {
+                a != addr && now.duration_since(rep.logged_at) >= interval
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<_>>();
+        expired.into_iter()
+            .filter_map(|addr| self.last.remove(&addr))
+            .filter(|rep| rep.suppressed > 0)
+            .collect()
+    }
+}
 
 impl<C, S> Clone for WarnLoggerInstance<C, S> {
     fn clone(&self) -> Self {
-        WarnLoggerInstance(PhantomData)
+        WarnLoggerInstance {
+            settings: self.settings.clone(),
+            phantom: PhantomData,
+        }
     }
 }
 
-impl<C: fmt::Display, S: fmt::Display> NewErrorLog<C, S> for WarnLogger {
+impl<C, S, P> NewErrorLog<C, S, P> for WarnLogger
+    where C: fmt::Display,
+          S: fmt::Display,
+          P: PoolAddress,
+{
     type ErrorLog = WarnLoggerInstance<C, S>;
-    fn construct(self) -> Self::ErrorLog {
-        WarnLoggerInstance(PhantomData)
+    fn construct(self, name: &str) -> Self::ErrorLog {
+        WarnLoggerInstance {
+            settings: Arc::new(Settings {
+                name: name.to_string(),
+                target: self.target
+                    .unwrap_or_else(|| module_path!().to_string()),
+                connection_error: self.connection_error,
+                sink_error: self.sink_error,
+                no_backends: self.no_backends,
+                shutdown: self.shutdown,
+                limiter: self.rate_limit
+                    .map(|i| Mutex::new(RateLimiter::new(i))),
+            }),
+            phantom: PhantomData,
+        }
     }
 }
 
-impl<C, S> ErrorLog for WarnLoggerInstance<C, S>
+impl<C, S, P> ErrorLog<P> for WarnLoggerInstance<C, S>
     where C: fmt::Display,
           S: fmt::Display,
+          P: PoolAddress,
 {
     type ConnectionError = C;
     type SinkError = S;
-    fn connection_error(&self, addr: SocketAddr, e: Self::ConnectionError) {
-        warn!("Connecting to {} failed: {}", addr, e);
+    fn connection_error(&self, addr: P, e: Self::ConnectionError) {
+        let s = &*self.settings;
+        let addr = addr.to_string();
+        s.log_error(s.connection_error, &addr,
+            format!("Connecting to {} failed: {}", addr, e));
+    }
+    fn sink_error(&self, addr: P, e: Self::SinkError) {
+        let s = &*self.settings;
+        let addr = addr.to_string();
+        s.log_error(s.sink_error, &addr,
+            format!("Connection to {} errored: {}", addr, e));
+    }
+    fn no_healthy_backends(&self) {
+        let s = &*self.settings;
+        log!(target: &s.target, s.no_backends,
+            "[{}] No healthy backends to send requests to", s.name);
     }
-    fn sink_error(&self, addr: SocketAddr, e: Self::SinkError) {
-        warn!("Connection to {} errored: {}", addr, e);
+    fn unknown_address(&self, addr: P) {
+        let s = &*self.settings;
+        let addr = addr.to_string();
+        s.log_error(s.no_backends, &addr,
+            format!("Request pinned to {} is dropped: \
+                address is not in the pool", addr));
     }
     /// Starting to shut down pool
     fn pool_shutting_down(&self, reason: ShutdownReason) {
-        warn!("Shutting down connection pool: {}", reason);
+        let s = &*self.settings;
+        log!(target: &s.target, s.shutdown,
+            "[{}] Shutting down connection pool: {}", s.name, reason);
     }
     /// This is triggered when pool done all the work and shut down entirely
     fn pool_closed(&self) {
@@ -96,7 +352,49 @@ impl fmt::Display for ShutdownReason {
         f.write_str(match *self {
             RequestStreamClosed => "request stream closed",
             AddressStreamClosed => "address stream closed",
+            Requested => "shutdown requested",
             __Nonexhaustive => unreachable!(),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::RateLimiter;
+
+    fn addr(n: u8) -> String {
+        format!("127.0.0.{}:80", n)
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut r = RateLimiter::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let sec = Duration::from_secs(1);
+        assert_eq!(r.check(&addr(1), "refused", start).0, Some(0));
+        assert_eq!(r.check(&addr(1), "refused", start + sec).0, None);
+        assert_eq!(r.check(&addr(1), "refused", start + sec*2).0, None);
+        // other address is not affected
+        assert_eq!(r.check(&addr(2), "refused", start + sec*2).0, Some(0));
+        // different message is logged immediately
+        assert_eq!(r.check(&addr(1), "timed out", start + sec*3).0, Some(2));
+        assert_eq!(r.check(&addr(1), "timed out", start + sec*4).0, None);
+        // same message is logged again after interval
+        assert_eq!(r.check(&addr(1), "timed out", start + sec*13).0, Some(1));
+        assert_eq!(r.check(&addr(1), "timed out", start + sec*14).0, None);
+        // suppressed count is returned when entry is evicted
+        let (res, evicted) = r.check(&addr(3), "refused", start + sec*30);
+        assert_eq!(res, Some(0));
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].message, "timed out");
+        assert_eq!(evicted[0].suppressed, 1);
+        // suppressed count is flushed by a message which is suppressed too
+        assert_eq!(r.check(&addr(3), "refused", start + sec*31).0, None);
+        assert_eq!(r.check(&addr(2), "refused", start + sec*32).0, Some(0));
+        let (res, evicted) = r.check(&addr(2), "refused", start + sec*41);
+        assert_eq!(res, None);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].suppressed, 1);
+    }
+}
@@ -0,0 +1,175 @@
+//! Stream of connection pool events
+//!
+//! This is an alternative to ``ErrorLog`` and ``metrics::Collect`` which
+//! allows any number of independent consumers to watch the pool.
+//! Subscribe with ``queue::Pool::events``.
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use futures::{Stream, StreamExt};
+use futures::channel::mpsc::UnboundedReceiver;
+
+use address::Endpoint;
+use error_log::ShutdownReason;
+
+
+/// An event happened in the connection pool
+///
+/// Addresses are reported as ``Endpoint`` values regardless of the address
+/// type of the pool.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Started establishing a connection to the address
+    Connecting(Endpoint),
+    /// Connection to the address is established
+    Connected(Endpoint),
+    /// Connection (or connection attempt) is gone
+    Disconnected(Endpoint, DisconnectReason),
+    /// Address is blacklisted until specified time
+    ///
+    /// The address may be unlisted later than that, see
+    /// ``Collect::blacklist_remove`` for details.
+    Blacklisted(Endpoint, Instant),
+    /// A set of addresses of the pool changed
+    AddressChanged {
+        /// Addresses that are new in this update
+        added: Vec<Endpoint>,
+        /// Addresses that are removed in this update
+        removed: Vec<Endpoint>,
+    },
+    /// Pool is started to shut down for the specified reason
+    Shutdown(ShutdownReason),
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// A reason connection is closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Error establishing connection (this also blacklists the address)
+    CantConnect,
+    /// Error initializing established connection (see ``initialize``)
+    CantInitialize,
+    /// Connection attempt aborted (address removed or pool shutting down)
+    Aborted,
+    /// Connection closed (address removed or pool shutting down)
+    Closed,
+    /// Connection errored when sending a request
+    Error,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// A stream of events returned from ``queue::Pool::events``
+///
+/// Stream ends when connection pool is fully closed. Note that events are
+/// buffered in unbounded channel, so stream must be read to avoid
+/// excessive memory usage.
+#[derive(Debug)]
+pub struct Events(Option<UnboundedReceiver<Event>>);
+
+pub(crate) use self::shared::Subscribers;
+
+mod shared {
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::task::{Context, Waker};
+    use futures::channel::mpsc::{unbounded, UnboundedSender};
+    use error_log::ShutdownReason;
+    use events::{Event, Events};
+
+    #[derive(Debug)]
+    struct Inner {
+        senders: Vec<UnboundedSender<Event>>,
+        shutdown: Option<ShutdownReason>,
+        closed: bool,
+        waiting: Vec<Waker>,
+    }
+
+    /// A list of subscribers shared between pool parts
+    #[derive(Debug, Clone)]
+    pub struct Subscribers(Arc<Mutex<Inner>>);
+
+    impl Subscribers {
+        pub fn new() -> Subscribers {
+            Subscribers(Arc::new(Mutex::new(Inner {
+                senders: Vec::new(),
+                shutdown: None,
+                closed: false,
+                waiting: Vec::new(),
+            })))
+        }
+        fn lock(&self) -> MutexGuard<'_, Inner> {
+            self.0.lock().expect("subscribers are not poisoned")
+        }
+        pub fn subscribe(&self) -> Events {
+            let mut inner = self.lock();
+            if inner.closed {
+                return Events(None);
+            }
+            let (tx, rx) = unbounded();
+            inner.senders.push(tx);
+            Events(Some(rx))
+        }
+        pub fn emit(&self, event: Event) {
+            let mut inner = self.lock();
+            inner.senders.retain(|s| {
+                s.unbounded_send(event.clone()).is_ok()
+            });
+        }
+        pub fn has_subscribers(&self) -> bool {
+            let inner = self.lock();
+            !inner.senders.is_empty()
+        }
+        /// Remembers the reason and emits ``Event::Shutdown``
+        ///
+        /// Only the first reason is remembered.
+        pub fn shutting_down(&self, reason: ShutdownReason) {
+            {
+                let mut inner = self.lock();
+                if inner.shutdown.is_none() {
+                    inner.shutdown = Some(reason.clone());
+                }
+            }
+            self.emit(Event::Shutdown(reason));
+        }
+        pub fn shutdown_reason(&self) -> Option<ShutdownReason> {
+            self.lock().shutdown.clone()
+        }
+        /// Returns true if pool is closed, otherwise current task is
+        /// notified when it's closed
+        pub fn poll_closed(&self, cx: &mut Context) -> bool {
+            let mut inner = self.lock();
+            if !inner.closed &&
+                !inner.waiting.iter().any(|w| w.will_wake(cx.waker()))
+            {
+                inner.waiting.push(cx.waker().clone());
+            }
+            inner.closed
+        }
+        pub fn is_closed(&self) -> bool {
+            self.lock().closed
+        }
+        /// Ends all event streams, used when pool is closed
+        pub fn close(&self) {
+            let mut inner = self.lock();
+            inner.closed = true;
+            inner.senders.clear();
+            for waker in inner.waiting.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Stream for Events {
+    type Item = Event;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Option<Event>>
+    {
+        match self.0 {
+            Some(ref mut rx) => rx.poll_next_unpin(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Failing requests when there are no healthy backends
+//!
+//! By default when there are no addresses, or all of them are blacklisted,
+//! requests wait in the queue until some backend becomes healthy again.
+//! With ``PoolConfig::fail_fast`` requests are failed instead, either
+//! immediately or when there are no healthy backends for a timeout.
+//!
+//! Only requests marked as ``Rejectable`` can be failed, ``call::Call``
+//! is rejectable and resolves to ``CallError::NoHealthyBackends``:
+//!
+//! ```rust,ignore
+//! let (mut pool, shutdown) = pool_for(connector)
+//!     .connect_to(address_stream)
+//!     .lazy_uniform_connections(2)
+//!     .fail_fast(FailFast::timeout(Duration::from_secs(1)))
+//!     .spawn_on(&Handle::current());
+//! ```
+//!
+//! Requests which can't be sent because of their routing hints (see
+//! ``route``) don't make the pool unhealthy, they wait for their hosts and
+//! are only failed when the whole pool is failing.
+//!
+//! Note: pool has no addresses until the first value of the address stream
+//! is received, so ``FailFast::immediate`` might fail requests sent right
+//! after the pool is created.
+use std::fmt;
+use std::time::Duration;
+
+use config::private::FailPolicy;
+
+
+/// A request that might be failed by the pool instead of being sent
+pub trait Rejectable: Sized {
+    /// Called when request is failed because there are no healthy backends
+    ///
+    /// The error contains the request itself.
+    fn rejected(error: NoHealthyBackends<Self>);
+}
+
+/// Error passed to ``Rejectable::rejected``
+pub struct NoHealthyBackends<I>(I);
+
+/// A policy which fails requests when there are no healthy backends
+#[derive(Debug, Clone)]
+pub struct FailFast {
+    timeout: Duration,
+}
+
+/// A policy which waits for a healthy backend indefinitely (default)
+#[derive(Debug, Clone)]
+pub struct Wait;
+
+impl FailFast {
+    /// Fail requests as soon as there are no healthy backends
+    pub fn immediate() -> FailFast {
+        FailFast { timeout: Duration::new(0, 0) }
+    }
+    /// Fail requests when there are no healthy backends for a timeout
+    ///
+    /// When timeout passes all waiting requests are failed, and new ones
+    /// are failed immediately until some backend becomes healthy again.
+    pub fn timeout(timeout: Duration) -> FailFast {
+        FailFast { timeout }
+    }
+}
+
+impl<I> NoHealthyBackends<I> {
+    /// Return ownership of the request
+    pub fn into_inner(self) -> I {
+        self.0
+    }
+}
+
+impl<I> FailPolicy<I> for Wait {
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+    fn reject(&self, _item: I) {
+        unreachable!("requests are never rejected");
+    }
+}
+
+impl<I: Rejectable> FailPolicy<I> for FailFast {
+    fn timeout(&self) -> Option<Duration> {
+        Some(self.timeout)
+    }
+    fn reject(&self, item: I) {
+        I::rejected(NoHealthyBackends(item))
+    }
+}
+
+impl<I> fmt::Display for NoHealthyBackends<I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("no healthy backends")
+    }
+}
+
+impl<I> fmt::Debug for NoHealthyBackends<I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("NoHealthyBackends(_)")
+    }
+}
+
+impl<I> ::std::error::Error for NoHealthyBackends<I> {
+    fn description(&self) -> &str {
+        "NoHealthyBackends"
+    }
+}
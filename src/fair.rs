@@ -0,0 +1,333 @@
+//! A queue which is fair across tenants
+//!
+//! Used instead of `futures::channel::mpsc` channel for ``config::FairQueue``.
+//! Every tenant has its own bounded queue, and requests are forwarded
+//! using deficit round-robin with unit cost per request.
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use futures::Stream;
+use futures::task::AtomicWaker;
+
+
+/// Key which tenant extractor returns, with the type erased
+pub trait TenantKey: fmt::Display + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn equals(&self, other: &dyn TenantKey) -> bool;
+}
+
+/// Tenant of a request
+#[derive(Clone)]
+pub struct Tenant {
+    hash: u64,
+    key: Arc<dyn TenantKey>,
+}
+
+pub type Extractor<V> = Arc<dyn Fn(&V) -> Tenant + Send + Sync>;
+pub type Report = Arc<dyn Fn(&Tenant, usize) + Send + Sync>;
+
+pub struct Sender<V> {
+    shared: Arc<Shared<V>>,
+}
+
+pub struct Receiver<V> {
+    shared: Arc<Shared<V>>,
+}
+
+pub enum SendError<V> {
+    /// Tenant's queue is full, if sent with `park` the waker is woken up
+    /// when there is a space in the queue
+    Full(V),
+    /// Receiver is closed or dropped
+    Closed(V),
+}
+
+struct Shared<V> {
+    state: Mutex<State<V>>,
+    task: AtomicWaker,
+    tenant: Extractor<V>,
+    report: Report,
+    capacity: usize,
+    quantum: usize,
+}
+
+struct State<V> {
+    /// Only tenants having queued requests are kept
+    tenants: HashMap<Tenant, TenantQueue<V>>,
+    /// Round-robin order of tenants, front one is being served
+    active: VecDeque<Tenant>,
+    /// Number of requests the front tenant can send in this round
+    deficit: usize,
+    senders: usize,
+    closed: bool,
+}
+
+struct TenantQueue<V> {
+    items: VecDeque<V>,
+    /// Senders waiting for a space in the queue
+    waiting: Vec<Waker>,
+}
+
+pub fn channel<V>(capacity: usize, quantum: usize,
+    tenant: Extractor<V>, report: Report)
+    -> (Sender<V>, Receiver<V>)
+{
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            tenants: HashMap::new(),
+            active: VecDeque::new(),
+            deficit: 0,
+            senders: 1,
+            closed: false,
+        }),
+        task: AtomicWaker::new(),
+        tenant, report, capacity, quantum,
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> TenantKey for T
+    where T: Hash + Eq + fmt::Display + Send + Sync + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn equals(&self, other: &dyn TenantKey) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+}
+
+impl Tenant {
+    pub fn new<T>(key: T) -> Tenant
+        where T: Hash + Eq + fmt::Display + Send + Sync + 'static,
+    {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Tenant { hash: hasher.finish(), key: Arc::new(key) }
+    }
+}
+
+impl Hash for Tenant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.hash.hash(state)
+    }
+}
+
+impl PartialEq for Tenant {
+    fn eq(&self, other: &Tenant) -> bool {
+        self.hash == other.hash && self.key.equals(&*other.key)
+    }
+}
+
+impl Eq for Tenant {}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.key.fmt(f)
+    }
+}
+
+impl fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tenant({})", self.key)
+    }
+}
+
+impl<V> Shared<V> {
+    fn lock(&self) -> MutexGuard<'_, State<V>> {
+        self.state.lock().expect("queue is not poisoned")
+    }
+}
+
+impl<V> State<V> {
+    /// Returns next item to send, its tenant and depth of its queue
+    fn pop(&mut self, quantum: usize)
+        -> Option<(V, Tenant, usize, Vec<Waker>)>
+    {
+        let tenant = self.active.front()?.clone();
+        if self.deficit == 0 {
+            self.deficit = quantum;
+        }
+        self.deficit -= 1;
+        let (item, depth, waiting) = {
+            let queue = self.tenants.get_mut(&tenant)
+                .expect("active tenant has a queue");
+            let item = queue.items.pop_front()
+                .expect("active tenant has requests");
+            let waiting = queue.waiting.drain(..).collect();
+            (item, queue.items.len(), waiting)
+        };
+        if depth == 0 {
+            self.tenants.remove(&tenant);
+            self.active.pop_front();
+            self.deficit = 0;
+        } else if self.deficit == 0 {
+            self.active.rotate_left(1);
+        }
+        Some((item, tenant, depth, waiting))
+    }
+}
+
+impl<V> Sender<V> {
+    /// Put item into the queue of its tenant
+    ///
+    /// If `park` is set and the queue is full, the waker is woken up when
+    /// there is a space in the queue.
+    pub fn send(&self, item: V, park: Option<&Waker>)
+        -> Result<(), SendError<V>>
+    {
+        let tenant = (self.shared.tenant)(&item);
+        let depth = {
+            let mut state = self.shared.lock();
+            if state.closed {
+                return Err(SendError::Closed(item));
+            }
+            let state = &mut *state;
+            let queue = match state.tenants.entry(tenant.clone()) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    state.active.push_back(tenant.clone());
+                    e.insert(TenantQueue {
+                        items: VecDeque::new(),
+                        waiting: Vec::new(),
+                    })
+                }
+            };
+            if queue.items.len() >= self.shared.capacity {
+                if let Some(waker) = park {
+                    if !queue.waiting.iter().any(|w| w.will_wake(waker)) {
+                        queue.waiting.push(waker.clone());
+                    }
+                }
+                return Err(SendError::Full(item));
+            }
+            queue.items.push_back(item);
+            queue.items.len()
+        };
+        self.shared.task.wake();
+        (self.shared.report)(&tenant, depth);
+        Ok(())
+    }
+}
+
+impl<V> Receiver<V> {
+    /// Stop accepting new items, queued items are still received
+    pub fn close(&mut self) {
+        let waiting = {
+            let mut state = self.shared.lock();
+            state.closed = true;
+            state.tenants.values_mut()
+                .flat_map(|q| q.waiting.drain(..))
+                .collect::<Vec<_>>()
+        };
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+}
+
+impl<V> Stream for Receiver<V> {
+    type Item = V;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<V>> {
+        let popped = {
+            let mut state = self.shared.lock();
+            match state.pop(self.shared.quantum) {
+                Some(popped) => popped,
+                None if state.closed || state.senders == 0 => {
+                    return Poll::Ready(None);
+                }
+                None => {
+                    // registered under the lock, so no item is missed
+                    self.shared.task.register(cx.waker());
+                    return Poll::Pending;
+                }
+            }
+        };
+        let (item, tenant, depth, waiting) = popped;
+        for waker in waiting {
+            waker.wake();
+        }
+        (self.shared.report)(&tenant, depth);
+        Poll::Ready(Some(item))
+    }
+}
+
+impl<V> Clone for Sender<V> {
+    fn clone(&self) -> Sender<V> {
+        self.shared.lock().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<V> Drop for Sender<V> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.shared.lock();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.shared.task.wake();
+        }
+    }
+}
+
+impl<V> Drop for Receiver<V> {
+    fn drop(&mut self) {
+        self.close();
+        let mut state = self.shared.lock();
+        state.tenants.clear();
+        state.active.clear();
+    }
+}
+
+impl<V> fmt::Debug for Sender<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("capacity", &self.shared.capacity)
+            .field("quantum", &self.shared.quantum)
+            .finish()
+    }
+}
+
+impl<V> fmt::Debug for Receiver<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("capacity", &self.shared.capacity)
+            .field("quantum", &self.shared.quantum)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use futures::StreamExt;
+    use futures::task::noop_waker_ref;
+    use super::{channel, Tenant};
+
+    #[test]
+    fn round_robin() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let (tx, mut rx) = channel(3, 2,
+            Arc::new(|&(t, _): &(&'static str, u32)| Tenant::new(t)),
+            Arc::new(|_, _| {}));
+        for i in 0..3 {
+            tx.send(("noisy", i), None).ok().unwrap();
+        }
+        assert!(tx.send(("noisy", 3), None).is_err());
+        tx.send(("quiet", 10), None).ok().unwrap();
+        let mut order = Vec::new();
+        while let Poll::Ready(Some((_, i))) = rx.poll_next_unpin(&mut cx) {
+            order.push(i);
+        }
+        assert_eq!(order, vec![0, 1, 10, 2]);
+    }
+}
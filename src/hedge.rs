@@ -0,0 +1,300 @@
+//! Hedged requests for reducing tail latency
+//!
+//! ``Hedged`` wraps a pool of ``Call`` items. If the response to a request
+//! isn't received within a delay (a percentile of the recently observed
+//! latencies), a copy of the request is sent and the first response
+//! wins. Only use it for idempotent requests.
+//!
+//! Pool must be configured with ``PoolConfig::routing`` so that the copy
+//! is sent to a different host than the original request (if there is
+//! more than one host):
+//!
+//! ```rust,ignore
+//! let (pool, _shutdown) = pool_for(connector)
+//!     .connect_to(address_stream)
+//!     .lazy_uniform_connections(2)
+//!     .routing()
+//!     .spawn_on(&Handle::current());
+//! let mut hedged = Hedged::new(pool, Hedge::new(), Handle::current());
+//! let response = hedged.call(request).await?;
+//! ```
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+
+use call::{Call, CallError, Peer, ResponseFuture};
+use metrics::Collect;
+use queue::Pool;
+use retry::Budget;
+use timer::{Timer, SharedTimer, Sleep};
+
+/// Delay is not computed until this number of responses is received
+const MIN_SAMPLES: usize = 10;
+/// Delay is recomputed each time this number of responses is received
+const RECOMPUTE_EVERY: usize = 16;
+
+
+/// Configuration of hedged requests
+#[derive(Debug, Clone)]
+pub struct Hedge {
+    percentile: f64,
+    min_delay: Duration,
+    window: usize,
+    reserve: f64,
+    ratio: f64,
+}
+
+/// A wrapper around the pool which sends hedged requests
+///
+/// Clones share the latency statistics and the budget.
+pub struct Hedged<Req, Resp, M, P=SocketAddr> {
+    pool: Pool<Call<Req, Resp, P>, M>,
+    timer: SharedTimer,
+    state: Arc<Mutex<State>>,
+}
+
+/// A future returned by ``Hedged::call``
+#[must_use = "futures do nothing unless polled"]
+pub struct HedgedFuture<Req, Resp, M, P=SocketAddr> {
+    pool: Pool<Call<Req, Resp, P>, M>,
+    request: Option<Req>,
+    primary: Option<ResponseFuture<Resp>>,
+    hedge: Option<ResponseFuture<Resp>>,
+    timeout: Option<Sleep>,
+    peer: Arc<Mutex<Option<P>>>,
+    started: Instant,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    policy: Hedge,
+    samples: VecDeque<Duration>,
+    since_update: usize,
+    delay: Option<Duration>,
+    budget: Budget,
+}
+
+impl Hedge {
+    /// Create a default policy
+    ///
+    /// By default request is hedged if it's not completed within 95th
+    /// percentile of the last 1000 responses, but not earlier than 10 ms
+    /// after it's sent. At most 10% of requests are hedged (with up to 10
+    /// in a burst).
+    pub fn new() -> Hedge {
+        Hedge {
+            percentile: 0.95,
+            min_delay: Duration::from_millis(10),
+            window: 1000,
+            reserve: 10.,
+            ratio: 0.1,
+        }
+    }
+    /// Set a percentile of latency after which request is hedged
+    ///
+    /// Value must be between 0 and 1, e.g. `0.95`.
+    pub fn percentile(mut self, value: f64) -> Hedge {
+        assert!(value > 0. && value <= 1.);
+        self.percentile = value;
+        self
+    }
+    /// Set minimum delay before sending a hedged request
+    pub fn min_delay(mut self, delay: Duration) -> Hedge {
+        self.min_delay = delay;
+        self
+    }
+    /// Set number of last responses used to compute the percentile
+    pub fn window(mut self, num: usize) -> Hedge {
+        assert!(num >= MIN_SAMPLES);
+        self.window = num;
+        self
+    }
+    /// Set budget of hedged requests
+    ///
+    /// Works like ``Retry::budget``: every request adds `ratio`, every
+    /// hedged request takes one, `reserve` is the burst size.
+    pub fn budget(mut self, reserve: u32, ratio: f64) -> Hedge {
+        assert!(ratio >= 0.);
+        self.reserve = reserve as f64;
+        self.ratio = ratio;
+        self
+    }
+}
+
+impl Default for Hedge {
+    fn default() -> Hedge {
+        Hedge::new()
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().expect("hedge state is not poisoned")
+}
+
+impl State {
+    fn record(&mut self, latency: Duration) {
+        if self.samples.len() >= self.policy.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+        self.since_update += 1;
+        if self.samples.len() >= MIN_SAMPLES &&
+            (self.delay.is_none() || self.since_update >= RECOMPUTE_EVERY)
+        {
+            let mut sorted = self.samples.iter().cloned().collect::<Vec<_>>();
+            sorted.sort();
+            let idx = ((sorted.len() - 1) as f64 * self.policy.percentile)
+                as usize;
+            self.delay = Some(sorted[idx].max(self.policy.min_delay));
+            self.since_update = 0;
+        }
+    }
+}
+
+impl<Req, Resp, P, M: Collect> Hedged<Req, Resp, M, P> {
+    /// Wrap the pool, timer is used to schedule hedged requests
+    pub fn new<T>(pool: Pool<Call<Req, Resp, P>, M>, policy: Hedge, timer: T)
+        -> Hedged<Req, Resp, M, P>
+        where T: Timer + 'static,
+    {
+        Hedged {
+            pool,
+            timer: Arc::new(timer),
+            state: Arc::new(Mutex::new(State {
+                budget: Budget::new(policy.reserve, policy.ratio),
+                samples: VecDeque::new(),
+                since_update: 0,
+                delay: None,
+                policy,
+            })),
+        }
+    }
+    /// Send a request and return a future of the response
+    ///
+    /// Like ``Pool::call`` this doesn't wait if the queue is full.
+    pub fn call(&mut self, request: Req) -> HedgedFuture<Req, Resp, M, P>
+        where Req: Clone,
+    {
+        let delay = {
+            let mut state = lock(&self.state);
+            state.budget.deposit();
+            state.delay
+        };
+        let peer = Arc::new(Mutex::new(None));
+        let copy = delay.map(|_| request.clone());
+        let (call, future) = Call::new(request);
+        let call = call.with_peer(Peer::Record(peer.clone()));
+        let started = Instant::now();
+        HedgedFuture {
+            primary: Some(self.pool.send_call(call, future)),
+            request: copy,
+            hedge: None,
+            timeout: delay.map(|d| self.timer.sleep_until(started + d)),
+            pool: self.pool.clone(),
+            peer, started,
+            state: self.state.clone(),
+        }
+    }
+    /// Returns underlying pool
+    pub fn pool(&self) -> &Pool<Call<Req, Resp, P>, M> {
+        &self.pool
+    }
+}
+
+impl<Req, Resp, P, M: Collect> Clone for Hedged<Req, Resp, M, P> {
+    fn clone(&self) -> Self {
+        Hedged {
+            pool: self.pool.clone(),
+            timer: self.timer.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<Req, Resp, P, M: Collect> HedgedFuture<Req, Resp, M, P> {
+    fn send_hedge(&mut self) {
+        let request = match self.request.take() {
+            Some(request) => request,
+            None => return,
+        };
+        if !lock(&self.state).budget.withdraw() {
+            return;
+        }
+        let (call, future) = Call::new(request);
+        let call = call.with_peer(Peer::Avoid(self.peer.clone()));
+        self.hedge = Some(self.pool.send_call(call, future));
+    }
+}
+
+// fields are never pinned
+impl<Req, Resp, M, P> Unpin for HedgedFuture<Req, Resp, M, P> {}
+
+impl<Req, Resp, P, M: Collect> Future for HedgedFuture<Req, Resp, M, P> {
+    type Output = Result<Resp, CallError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<Resp, CallError>>
+    {
+        let this = self.get_mut();
+        if let Some(Poll::Ready(())) = this.timeout.as_mut()
+            .map(|t| t.poll_unpin(cx))
+        {
+            this.timeout = None;
+            this.send_hedge();
+        }
+        let mut error = None;
+        for slot in &mut [&mut this.primary, &mut this.hedge] {
+            let result = match **slot {
+                Some(ref mut future) => future.poll_unpin(cx),
+                None => continue,
+            };
+            match result {
+                Poll::Ready(Ok(response)) => {
+                    lock(&this.state).record(this.started.elapsed());
+                    return Poll::Ready(Ok(response));
+                }
+                Poll::Pending => {}
+                Poll::Ready(Err(e)) => {
+                    **slot = None;
+                    error = Some(e);
+                }
+            }
+        }
+        if this.primary.is_none() && this.hedge.is_none() {
+            // no sense to hedge a failed request, retry policy is for that
+            return Poll::Ready(
+                Err(error.expect("at least one request failed")));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{Hedge, State, Budget};
+
+    #[test]
+    fn percentile() {
+        let policy = Hedge::new().percentile(0.9)
+            .min_delay(Duration::from_millis(5));
+        let mut state = State {
+            budget: Budget::new(0., 0.),
+            samples: Default::default(),
+            since_update: 0,
+            delay: None,
+            policy,
+        };
+        for i in 1..10 {
+            state.record(Duration::from_millis(i * 10));
+        }
+        assert_eq!(state.delay, None);
+        state.record(Duration::from_millis(100));
+        assert_eq!(state.delay, Some(Duration::from_millis(90)));
+    }
+}
@@ -0,0 +1,306 @@
+//! Initialization of established connections
+//!
+//! Some protocols need an exchange after connection is established, for
+//! example authentication or selecting a database. This can be done in the
+//! connect function, but then errors of the exchange are handled like
+//! network errors. With ``PartialConfig::initialize`` it's a separate stage
+//! with its own error type and timeout:
+//!
+//! ```rust,ignore
+//! let (mut pool, shutdown) = pool_for(connector)
+//!     .initialize(|addr, conn| authenticate(conn, &password))
+//!     .initialize_timeout(Duration::from_secs(5))
+//!     .connect_to(address_stream)
+//!     .spawn_on(&Handle::current());
+//! ```
+//!
+//! Errors are reported to ``ErrorLog::connection_error`` as ``InitError``.
+//! Initialization errors don't blacklist the address, only the failed
+//! connection is established again after the reconnect timeout.
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+
+use connect::Connect;
+use timer::{Timer, Sleep};
+
+
+/// This is a trait that is used for initializing an established connection
+///
+/// Usually just passing a closure is good enough
+pub trait Initialize<P, S> {
+    /// Connection used by the pool, may be different from the one passed
+    /// to `initialize`
+    type Connection;
+    /// Error of initialization
+    type Error;
+    /// A future returned by `initialize` method
+    type Future: Future<Output=Result<Self::Connection, Self::Error>>;
+    /// Initialize a connection to the specified address
+    fn initialize(&mut self, address: P, connection: S) -> Self::Future;
+}
+
+/// A connector which initializes established connections
+///
+/// Create it with ``PartialConfig::initialize``.
+#[derive(Debug, Clone)]
+pub struct Initialized<C, I> {
+    connector: C,
+    initializer: I,
+    timeout: Option<Duration>,
+}
+
+/// Error of a connector which initializes connections
+#[derive(Debug)]
+pub enum InitError<C, I> {
+    /// Error establishing a connection
+    Connect(C),
+    /// Error initializing an established connection
+    Initialize(I),
+    /// Initialization is not finished in time
+    Timeout,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// A future returned by ``Initialized`` connector
+pub struct InitFuture<C, I>
+    where C: Connect,
+          I: Initialize<C::Address, C::Connection>,
+{
+    state: State<C::Future, I::Future>,
+    address: Option<C::Address>,
+    initializer: Option<I>,
+    timeout: Option<(Duration, Arc<dyn Timer>)>,
+}
+
+enum State<C, I> {
+    Connecting(Pin<Box<C>>),
+    Initializing(Pin<Box<I>>, Option<Sleep>),
+}
+
+impl<T, P, S, F, R, E> Initialize<P, S> for T
+    where T: FnMut(P, S) -> F,
+          F: Future<Output=Result<R, E>>,
+{
+    type Connection = R;
+    type Error = E;
+    type Future = F;
+    fn initialize(&mut self, address: P, connection: S) -> F {
+        (self)(address, connection)
+    }
+}
+
+impl<C, I> Initialized<C, I> {
+    pub(crate) fn new(connector: C, initializer: I) -> Initialized<C, I> {
+        Initialized {
+            connector,
+            initializer,
+            timeout: None,
+        }
+    }
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+}
+
+impl<C, I> Initialized<C, I>
+    where C: Connect,
+          I: Initialize<C::Address, C::Connection> + Clone,
+{
+    fn start(&self, future: C::Future, address: C::Address,
+        timer: Option<&Arc<dyn Timer>>)
+        -> InitFuture<C, I>
+    {
+        InitFuture {
+            state: State::Connecting(Box::pin(future)),
+            address: Some(address),
+            initializer: Some(self.initializer.clone()),
+            timeout: match (self.timeout, timer) {
+                (Some(dur), Some(timer)) => Some((dur, timer.clone())),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl<C, I> Connect for Initialized<C, I>
+    where C: Connect,
+          I: Initialize<C::Address, C::Connection> + Clone,
+{
+    type Address = C::Address;
+    type Connection = I::Connection;
+    type Error = InitError<C::Error, I::Error>;
+    type Future = InitFuture<C, I>;
+    fn connect(&mut self, address: C::Address) -> InitFuture<C, I> {
+        let future = self.connector.connect(address.clone());
+        self.start(future, address, None)
+    }
+    fn connect_with_timer(&mut self, address: C::Address,
+        timer: &Arc<dyn Timer>)
+        -> InitFuture<C, I>
+    {
+        let future = self.connector.connect_with_timer(address.clone(),
+            timer);
+        self.start(future, address, Some(timer))
+    }
+    fn is_initialize_error(&self, error: &Self::Error) -> bool {
+        match *error {
+            InitError::Connect(ref e) => {
+                self.connector.is_initialize_error(e)
+            }
+            InitError::Initialize(_) | InitError::Timeout => true,
+            InitError::__Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+// inner futures are pinned in boxes, other fields are never pinned
+impl<C, I> Unpin for InitFuture<C, I>
+    where C: Connect,
+          I: Initialize<C::Address, C::Connection>,
+{}
+
+impl<C, I> Future for InitFuture<C, I>
+    where C: Connect,
+          I: Initialize<C::Address, C::Connection>,
+{
+    type Output = Result<I::Connection, InitError<C::Error, I::Error>>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            let connection = match this.state {
+                State::Connecting(ref mut future) => {
+                    match future.as_mut().poll(cx) {
+                        Poll::Ready(Ok(connection)) => connection,
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Err(e)) => {
+                            return Poll::Ready(Err(InitError::Connect(e)));
+                        }
+                    }
+                }
+                State::Initializing(ref mut future, ref mut sleep) => {
+                    match future.as_mut().poll(cx) {
+                        Poll::Ready(Ok(connection)) => {
+                            return Poll::Ready(Ok(connection));
+                        }
+                        Poll::Pending => {}
+                        Poll::Ready(Err(e)) => {
+                            return Poll::Ready(Err(InitError::Initialize(e)));
+                        }
+                    }
+                    if let Some(ref mut sleep) = *sleep {
+                        if sleep.poll_unpin(cx).is_ready() {
+                            return Poll::Ready(Err(InitError::Timeout));
+                        }
+                    }
+                    return Poll::Pending;
+                }
+            };
+            let address = this.address.take().expect("poll invariant");
+            let mut initializer = this.initializer.take()
+                .expect("poll invariant");
+            let sleep = this.timeout.take().map(|(timeout, timer)| {
+                timer.sleep_until(Instant::now() + timeout)
+            });
+            this.state = State::Initializing(
+                Box::pin(initializer.initialize(address, connection)), sleep);
+        }
+    }
+}
+
+impl<C, I> fmt::Debug for InitFuture<C, I>
+    where C: Connect,
+          I: Initialize<C::Address, C::Connection>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InitFuture")
+            .field("initializing", &match self.state {
+                State::Connecting(..) => false,
+                State::Initializing(..) => true,
+            })
+            .finish()
+    }
+}
+
+impl<C: fmt::Display, I: fmt::Display> fmt::Display for InitError<C, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InitError::*;
+        match *self {
+            Connect(ref e) => fmt::Display::fmt(e, f),
+            Initialize(ref e) => write!(f, "initialization failed: {}", e),
+            Timeout => f.write_str("initialization timed out"),
+            __Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+impl<C, I> ::std::error::Error for InitError<C, I>
+    where C: ::std::error::Error,
+          I: ::std::error::Error,
+{
+    fn description(&self) -> &str {
+        "connection error"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+    use futures::FutureExt;
+    use futures::executor::block_on;
+    use futures::future::{pending, ready, Pending, Ready};
+    use futures::task::noop_waker_ref;
+    use connect::Connect;
+    use timer::{Timer, Sleep};
+    use super::{Initialized, InitError};
+
+    struct Expired;
+
+    impl Timer for Expired {
+        fn sleep_until(&self, _deadline: Instant) -> Sleep {
+            Box::pin(ready(()))
+        }
+    }
+
+    fn connect(_: SocketAddr) -> Ready<Result<u32, &'static str>> {
+        ready(Ok(7))
+    }
+
+    #[test]
+    fn stages() {
+        let addr: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let mut conn = Initialized::new(connect,
+            |_: SocketAddr, _: u32| ready(Err::<u32, _>("denied")));
+        match block_on(conn.connect(addr)) {
+            Err(e) => {
+                assert!(conn.is_initialize_error(&e));
+                assert_eq!(e.to_string(), "initialization failed: denied");
+            }
+            Ok(_) => panic!("initialization error expected"),
+        }
+        assert!(!conn.is_initialize_error(&InitError::Connect("refused")));
+
+        let mut conn = Initialized::new(connect,
+            |_: SocketAddr, _: u32| -> Pending<Result<u32, &'static str>> {
+                pending()
+            });
+        conn.set_timeout(Duration::from_secs(1));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        // timeout is only applied when pool's timer is passed
+        assert!(conn.connect(addr).poll_unpin(&mut cx).is_pending());
+        let timer: Arc<dyn Timer> = Arc::new(Expired);
+        match conn.connect_with_timer(addr, &timer).poll_unpin(&mut cx) {
+            Poll::Ready(Err(InitError::Timeout)) => {}
+            _ => panic!("timeout expected"),
+        }
+    }
+}
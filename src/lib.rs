@@ -19,29 +19,61 @@
 //!
 //! ```rust,ignore
 //!
-//! let mut pool =
-//!     pool_for(|addr| connect(addr))
-//!     .connect_to(ns.subscribe_many(address, default_port))
+//! let (mut pool, shutdown) =
+//!     pool_for(|addr| TcpStream::connect(addr)
+//!                     .map_ok(|sock| Framed::new(sock, Codec)))
+//!     .connect_to_static(&addresses)
 //!     .lazy_uniform_connections(2)
 //!     .with_queue_size(10)
-//!     .spawn_on(&core.handle());
+//!     .spawn_on(&Handle::current());
 //!
+//! pool.send(request).await?;
 //! ```
 //!
+//! Pool is driven by a task spawned on `tokio` runtime, so connections,
+//! requests and address streams must be `Send`.
+//!
+//! # Features
+//!
+//! * `tracing` -- emit `tracing` spans for every connection attempt and
+//!   connection lifetime (with the address of the peer), and an event in
+//!   the connection span for every request dispatched to it
+//! * `serde` -- allows deserializing ``settings::Settings`` from
+//!   configuration files
+//!
 #[macro_use] extern crate log;
 extern crate abstract_ns;
 extern crate futures;
 extern crate rand;
-extern crate tokio_core;
+extern crate tokio;
 extern crate void;
+#[cfg(feature="tracing")] extern crate tracing;
+#[cfg(feature="serde")] #[macro_use] extern crate serde;
 
+pub mod address;
 mod connect;
+mod buffer;
+mod fair;
 mod basic;
+pub mod call;
+pub mod concurrency;
 pub mod queue;
+pub mod registry;
 pub mod error_log;
+pub mod events;
+pub mod fail_fast;
+pub mod hedge;
+pub mod initialize;
 pub mod metrics;
+pub mod rate_limit;
 pub mod uniform;
 pub mod config;
+pub mod settings;
+pub mod shutdown;
+pub mod retry;
+pub mod route;
+pub mod sharded;
+pub mod timer;
 
 pub use basic::pool_for;
-pub use connect::Connect;
+pub use connect::{Connect, ConnectFn, connect_fn};
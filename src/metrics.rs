@@ -1,14 +1,24 @@
 //! Metrics trait and no-op implementation
+use std::fmt;
+use std::time::Duration;
+
+use address::Endpoint;
+
 
 /// An object implementing trait may collect metrics of a connection pool
 pub trait Collect: Clone + Send + Sync {
     /// We started establishing connection
     ///
     /// This pairs either with ``connection`` on success
-    /// or ``connection_abort`` and ``connection_error`` on error
+    /// or ``connection_abort``, ``connection_error`` and
+    /// ``initialize_error`` on error
     fn connection_attempt(&self) {}
     /// Error establishing connection
     fn connection_error(&self) {}
+    /// Error initializing established connection (see ``initialize``)
+    ///
+    /// Unlike ``connection_error`` this doesn't blacklist the address.
+    fn initialize_error(&self) {}
 
     /// Aborted connection attempt (name changed, and doesn't include address)
     fn connection_abort(&self) {}
@@ -34,6 +44,38 @@ pub trait Collect: Clone + Send + Sync {
     /// Note: this might not mean that request is already sent as we can't
     /// control the underlying sinks used.
     fn request_forwarded(&self) {}
+    /// Request lost on a broken connection (or waiting for its hosts
+    /// longer than ``uniform::Config::park_timeout``) is dispatched again
+    fn request_retried(&self) {}
+    /// Request lost on a broken connection (or waiting for its hosts too
+    /// long) is dropped because retry budget or attempt limit is exhausted
+    fn retry_exhausted(&self) {}
+    /// Request is dropped because it's pinned to a host which is not in
+    /// the pool (see ``route::Route::pinned``), due to queue overflow
+    /// (see ``queue::Overflow``), or by ``queue::Discipline::Codel``
+    fn request_dropped(&self) {}
+    /// Request is failed because there are no healthy backends (see
+    /// ``fail_fast::FailFast``)
+    fn request_rejected(&self) {}
+    /// Request waited for a token of the rate limiter before forwarding
+    ///
+    /// Reported only for requests which had to wait (see
+    /// ``rate_limit::RateLimit``).
+    fn request_throttled(&self, _delay: Duration) {}
+    /// Concurrency limit of the host has changed
+    ///
+    /// Only reported when pool is configured with
+    /// ``PoolConfig::adaptive_concurrency``, also reported when host is
+    /// added to the pool.
+    fn concurrency_limit(&self, _host: &Endpoint, _limit: usize) {}
+    /// Number of requests in the queue of a tenant has changed
+    ///
+    /// Only reported when pool is configured with
+    /// ``PoolConfig::with_fair_queue``. Depth of zero means the tenant has
+    /// no queued requests anymore.
+    fn tenant_queue_depth(&self, _tenant: &dyn fmt::Display,
+        _depth: usize)
+    {}
 
     /// Connection pool is closed
     fn pool_closed(&self) {}
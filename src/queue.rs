@@ -1,15 +1,24 @@
 //! A queue (buffer) of requests sent to connection pool
 use std::fmt;
-use futures::{AsyncSink, Stream, StartSend, Poll, Async};
-use futures::sync::mpsc::{self, channel, Sender};
-use futures::sink::Sink;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures::{Sink, Stream, StreamExt};
+use futures::channel::mpsc::{self, channel, Sender};
 use futures::stream::Fuse;
-use futures::future::Future;
-use tokio_core::reactor::Handle;
 
 use metrics::Collect;
 use error_log::{ErrorLog, ShutdownReason};
-use config::{Queue, DefaultQueue, private};
+use events::{Events, Subscribers};
+use config::{Queue, FairQueue, DefaultQueue, PoolFuture, private};
+use config::private::{AsyncSink, Mux};
+use buffer;
+use fair;
+use shutdown::{Shutdown, Mode};
 
 
 /// Pool is an object you use to access a connection pool
@@ -20,201 +29,660 @@ use config::{Queue, DefaultQueue, private};
 /// very important to collect metrics at this side of a channel.
 #[derive(Debug)]
 pub struct Pool<V, M> {
-    channel: Sender<V>,
+    channel: Channel<V>,
+    /// Item accepted by `start_send` but waiting for a space in the queue
+    ///
+    /// It's only accessed mutably, lock keeps the pool `Sync`.
+    pending: Mutex<Option<V>>,
     metrics: M,
+    events: Subscribers,
 }
 
-/// Error returned by the sink, when underlying pool is closed
+/// What to do when a request is sent to a pool with a full queue
+///
+/// See ``PoolConfig::with_queue``.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(Deserialize))]
+#[cfg_attr(feature="serde", serde(rename_all="kebab-case"))]
+pub enum Overflow {
+    /// Wait until there is a space in the queue (default)
+    ///
+    /// `poll_ready` of the sink returns `Poll::Pending`.
+    Backpressure,
+    /// Fail with ``PoolError::QueueFull``
+    Reject,
+    /// Drop the oldest request in the queue and put the new one
+    DropOldest,
+    /// Drop the request being sent
+    DropNewest,
+    #[doc(hidden)]
+    #[cfg_attr(feature="serde", serde(skip_deserializing))]
+    __Nonexhaustive,
+}
+
+/// Order in which queued requests are forwarded to connections
+///
+/// Both ``Codel`` and ``AdaptiveLifo`` detect overload by the standing
+/// queue delay: the queue is overloaded when requests have been waiting
+/// longer than `target` for at least `interval`. Delay is measured from the
+/// time the request was put into the queue by ``Pool`` sink, and an empty
+/// queue resets the measurement.
 ///
-/// The error contains underlying item that was sent using `start_send`
-pub struct QueueError<V>(V);
+/// See ``PoolConfig::queue_discipline``.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Discipline {
+    /// First in, first out (default)
+    Fifo,
+    /// Drop requests using the CoDel algorithm (RFC 8289)
+    ///
+    /// When the queue gets overloaded one request is dropped, and while
+    /// the delay stays above target the next ones are dropped one at a
+    /// time, each `interval / sqrt(count)` after the previous drop, so
+    /// the drop rate increases slowly until the delay gets below target.
+    ///
+    /// Dropped requests are counted by ``Collect::request_dropped`` and
+    /// passed to the callback set by ``PoolConfig::on_queue_drop``.
+    Codel {
+        /// Acceptable queue delay
+        target: Duration,
+        /// Time the delay must stay above target to detect overload
+        interval: Duration,
+    },
+    /// Forward the newest requests first while the queue is overloaded
+    ///
+    /// Under overload old requests are likely to be timed out by the
+    /// client anyway, so serving new ones first keeps latency low for
+    /// at least some of them.
+    AdaptiveLifo {
+        /// Acceptable queue delay
+        target: Duration,
+        /// Time the delay must stay above target to detect overload
+        interval: Duration,
+    },
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+#[derive(Debug)]
+enum Channel<V> {
+    Bounded(Sender<V>),
+    Buffer(buffer::Sender<V>),
+    Fair(fair::Sender<V>),
+}
+
+#[derive(Debug)]
+pub(crate) enum Receiver<V> {
+    Bounded(mpsc::Receiver<V>),
+    Buffer(buffer::Receiver<V>),
+    Fair(fair::Receiver<V>),
+}
+
+/// Error returned when underlying pool is closed
+///
+/// The error contains underlying item that was sent to the pool
+pub struct QueueError<V>(pub(crate) V);
+
+/// Error returned by ``Pool`` sink
+pub enum PoolError<V> {
+    /// Pool is closed, the reason is unknown
+    Closed,
+    /// Pool is shut down for the specified reason
+    ///
+    /// Returned once the pool is closed. While pool shuts down gracefully
+    /// flushing succeeds, as requests which are already in the queue are
+    /// still processed (but no new ones are accepted).
+    Shutdown(ShutdownReason),
+    /// Item is rejected by `start_send` because pool is closed
+    Rejected(V),
+    /// Item is rejected by `start_send` because the queue is full
+    ///
+    /// Only returned when queue is configured with ``Overflow::Reject``.
+    QueueFull(V),
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// A future returned by ``Pool::closed``
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Closed(Subscribers);
 
 
 /// This is similar to `Forward` from `futures` but has metrics and errors
 #[derive(Debug)]
 #[must_use = "futures do nothing unless polled"]
-struct ForwardFuture<S, M, E>
-    where S: Sink
-{
-     receiver: Fuse<mpsc::Receiver<S::SinkItem>>,
-     buffer: Option<S::SinkItem>,
+pub(crate) struct ForwardFuture<S, I, M, E, P> {
+     receiver: Fuse<Receiver<I>>,
+     buffer: Option<I>,
      metrics: M,
      errors: E,
+     events: Subscribers,
      sink: S,
+     shutdown: Shutdown,
+     shutting_down: bool,
+     phantom: PhantomData<fn(P)>,
 }
 
-impl<I: 'static, M> private::NewQueue<I, M> for DefaultQueue {
+impl<I: Send + 'static, M> private::NewQueue<I, M> for DefaultQueue {
     type Pool = Pool<I, M>;
-    fn spawn_on<S, E>(self, pool: S, err: E, metrics: M, handle: &Handle)
-        -> Self::Pool
-        where S: Sink<SinkItem=I, SinkError=private::Done> + 'static,
-              E: ErrorLog + 'static,
+    fn build<S, E, P>(self, pool: S, err: E, metrics: M,
+        events: Subscribers, shutdown: Shutdown)
+        -> (Self::Pool, PoolFuture)
+        where S: Mux<I> + Send + 'static,
+              E: ErrorLog<P> + Send + 'static,
+              P: 'static,
               M: Collect + 'static,
     {
-        Queue(100).spawn_on(pool, err, metrics, handle)
+        Queue::new(100)
+            .build::<S, E, P>(pool, err, metrics, events, shutdown)
     }
 }
 
-impl<I: 'static, M> private::NewQueue<I, M> for Queue {
+impl<I: Send + 'static, M, D> private::NewQueue<I, M> for Queue<D>
+    where D: private::DropCallback<I>,
+{
     type Pool = Pool<I, M>;
-    fn spawn_on<S, E>(self, pool: S, e: E, metrics: M, handle: &Handle)
-        -> Self::Pool
-        where S: Sink<SinkItem=I, SinkError=private::Done> + 'static,
-              E: ErrorLog + 'static,
+    fn build<S, E, P>(self, pool: S, e: E, metrics: M,
+        events: Subscribers, shutdown: Shutdown)
+        -> (Self::Pool, PoolFuture)
+        where S: Mux<I> + Send + 'static,
+              E: ErrorLog<P> + Send + 'static,
+              P: 'static,
               M: Collect + 'static,
     {
-        // one item is buffered ForwardFuture
-        let buf_size = self.0.saturating_sub(1);
-        let (tx, rx) = channel(buf_size);
-        handle.spawn(ForwardFuture {
-            receiver: rx.fuse(),
-            metrics: metrics.clone(),
-            errors: e,
-            sink: pool,
-            buffer: None,
-        });
-        return Pool {
+        let (tx, rx) = match (self.overflow, self.discipline) {
+            (Overflow::Backpressure, Discipline::Fifo) => {
+                // one item is buffered ForwardFuture
+                let (tx, rx) = channel(self.size.saturating_sub(1));
+                (Channel::Bounded(tx), Receiver::Bounded(rx))
+            }
+            (policy, discipline) => {
+                let callback = self.on_drop.into_callback();
+                let m = metrics.clone();
+                let (tx, rx) = buffer::channel(self.size, policy, discipline,
+                    Arc::new(move |item| {
+                        m.request_dropped();
+                        if let Some(ref callback) = callback {
+                            callback(item);
+                        }
+                    }));
+                (Channel::Buffer(tx), Receiver::Buffer(rx))
+            }
+        };
+        let future = ForwardFuture::<_, _, _, _, P>::new(rx, pool,
+            metrics.clone(), e, events.clone(), shutdown);
+        let pool = Pool {
             channel: tx,
+            pending: Mutex::new(None),
             metrics,
+            events,
         };
+        return (pool, Box::pin(future));
     }
 }
 
+impl<I: Send + 'static, M, K> private::NewQueue<I, M> for FairQueue<K>
+    where K: private::TenantOf<I>,
+{
+    type Pool = Pool<I, M>;
+    fn build<S, E, P>(self, pool: S, e: E, metrics: M,
+        events: Subscribers, shutdown: Shutdown)
+        -> (Self::Pool, PoolFuture)
+        where S: Mux<I> + Send + 'static,
+              E: ErrorLog<P> + Send + 'static,
+              P: 'static,
+              M: Collect + 'static,
+    {
+        let m = metrics.clone();
+        let (tx, rx) = fair::channel(self.capacity, self.quantum,
+            self.tenant.into_extractor(),
+            Arc::new(move |tenant, depth| {
+                m.tenant_queue_depth(tenant, depth)
+            }));
+        let future = ForwardFuture::<_, _, _, _, P>::new(
+            Receiver::Fair(rx),
+            pool, metrics.clone(), e, events.clone(), shutdown);
+        let pool = Pool {
+            channel: Channel::Fair(tx),
+            pending: Mutex::new(None),
+            metrics,
+            events,
+        };
+        (pool, Box::pin(future))
+    }
+}
 
 trait AssertTraits: Clone + Send + Sync {}
 impl<V: Send, M: Collect> AssertTraits for Pool<V, M> {}
 
+impl<V> Clone for Channel<V> {
+    fn clone(&self) -> Self {
+        match *self {
+            Channel::Bounded(ref tx) => Channel::Bounded(tx.clone()),
+            Channel::Buffer(ref tx) => Channel::Buffer(tx.clone()),
+            Channel::Fair(ref tx) => Channel::Fair(tx.clone()),
+        }
+    }
+}
+
+impl<V> Receiver<V> {
+    fn close(&mut self) {
+        match *self {
+            Receiver::Bounded(ref mut rx) => rx.close(),
+            Receiver::Buffer(ref mut rx) => rx.close(),
+            Receiver::Fair(ref mut rx) => rx.close(),
+        }
+    }
+}
+
+impl<V> From<mpsc::Receiver<V>> for Receiver<V> {
+    fn from(rx: mpsc::Receiver<V>) -> Receiver<V> {
+        Receiver::Bounded(rx)
+    }
+}
+
+impl<V> Stream for Receiver<V> {
+    type Item = V;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Option<V>>
+    {
+        match *self {
+            Receiver::Bounded(ref mut rx) => rx.poll_next_unpin(cx),
+            Receiver::Buffer(ref mut rx) => rx.poll_next_unpin(cx),
+            Receiver::Fair(ref mut rx) => rx.poll_next_unpin(cx),
+        }
+    }
+}
+
 impl<V, M: Clone> Clone for Pool<V, M> {
     fn clone(&self) -> Self {
         Pool {
             channel: self.channel.clone(),
+            pending: Mutex::new(None),
             metrics: self.metrics.clone(),
+            events: self.events.clone(),
         }
     }
 }
 
-impl<S, M, E> ForwardFuture<S, M, E>
-    where S: Sink<SinkError=private::Done>,
+// fields are never pinned
+impl<V, M> Unpin for Pool<V, M> {}
+impl<S, I, M, E, P> Unpin for ForwardFuture<S, I, M, E, P> {}
+
+impl<S, I, M, E, P> ForwardFuture<S, I, M, E, P>
+    where S: Mux<I>,
           M: Collect,
-          E: ErrorLog,
+          E: ErrorLog<P>,
 {
-    fn poll_forever(&mut self) -> Async<()> {
+    pub(crate) fn new(receiver: Receiver<I>, sink: S,
+        metrics: M, errors: E, events: Subscribers, shutdown: Shutdown)
+        -> ForwardFuture<S, I, M, E, P>
+    {
+        ForwardFuture {
+            receiver: receiver.fuse(),
+            buffer: None,
+            metrics, errors, events, sink, shutdown,
+            shutting_down: false,
+            phantom: PhantomData,
+        }
+    }
+    fn shutting_down(&mut self) {
+        if !self.shutting_down {
+            self.shutting_down = true;
+            self.errors.pool_shutting_down(ShutdownReason::Requested);
+            self.events.shutting_down(ShutdownReason::Requested);
+        }
+    }
+    fn poll_forever(&mut self, cx: &mut Context) -> Poll<()> {
+        match self.shutdown.poll(cx) {
+            Mode::Running => {}
+            Mode::Graceful(_) => {
+                if !self.shutting_down {
+                    self.shutting_down();
+                    // queued requests are still received and forwarded,
+                    // then the sink is closed as if all pools were dropped
+                    self.receiver.get_mut().close();
+                }
+            }
+            Mode::Immediate => {
+                self.shutting_down();
+                return Poll::Ready(());
+            }
+        }
         if let Some(item) = self.buffer.take() {
-            match self.sink.start_send(item) {
+            match self.sink.start_send(cx, item) {
                 Ok(AsyncSink::Ready) => {
                     self.metrics.request_forwarded();
                 }
                 Ok(AsyncSink::NotReady(item)) => {
                     self.buffer = Some(item);
-                    return Async::NotReady;
+                    return Poll::Pending;
                 }
-                Err(private::Done) => return Async::Ready(()),
+                Err(private::Done) => return Poll::Ready(()),
             }
         }
 
         let was_done = self.receiver.is_done();
         loop {
-            match self.receiver.poll() {
-                Ok(Async::Ready(Some(item))) => {
-                    match self.sink.start_send(item) {
+            match self.receiver.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    match self.sink.start_send(cx, item) {
                         Ok(AsyncSink::Ready) => {
                             self.metrics.request_forwarded();
                             continue;
                         }
                         Ok(AsyncSink::NotReady(item)) => {
                             self.buffer = Some(item);
-                            return Async::NotReady;
+                            return Poll::Pending;
                         }
-                        Err(private::Done) => return Async::Ready(()),
+                        Err(private::Done) => return Poll::Ready(()),
                     }
                 }
-                Ok(Async::Ready(None)) => {
-                    if !was_done {
+                Poll::Ready(None) => {
+                    if !was_done && !self.shutting_down {
                         self.errors.pool_shutting_down(
                             ShutdownReason::RequestStreamClosed);
+                        self.events.shutting_down(
+                            ShutdownReason::RequestStreamClosed);
                     }
-                    match self.sink.close() {
-                        Ok(Async::NotReady) => {
-                            return Async::NotReady;
+                    match self.sink.close(cx) {
+                        Poll::Pending => {
+                            return Poll::Pending;
                         }
-                        Ok(Async::Ready(())) | Err(private::Done) => {
-                            return Async::Ready(());
+                        Poll::Ready(_) => {
+                            return Poll::Ready(());
                         }
                     }
                 }
-                Ok(Async::NotReady) => match self.sink.poll_complete() {
-                    Ok(_) => {
-                        return Async::NotReady;
+                Poll::Pending => match self.sink.poll_complete(cx) {
+                    Poll::Ready(Err(private::Done)) => {
+                        return Poll::Ready(());
                     }
-                    Err(private::Done) => {
-                        return Async::Ready(());
+                    _ => {
+                        return Poll::Pending;
                     }
                 }
-                // No errors in channel receiver
-                Err(()) => unreachable!(),
             }
         }
     }
 }
 
-impl<S, M, E> Future for ForwardFuture<S, M, E>
-    where S: Sink<SinkError=private::Done>,
+impl<S, I, M, E, P> Future for ForwardFuture<S, I, M, E, P>
+    where S: Mux<I>,
           M: Collect,
-          E: ErrorLog,
+          E: ErrorLog<P>,
 {
-    type Item = ();
-    type Error = ();  // Really Void
-    fn poll(&mut self) -> Result<Async<()>, ()> {
-        match self.poll_forever() {
-            Async::NotReady => Ok(Async::NotReady),
-            Async::Ready(()) => {
-                self.errors.pool_closed();
-                self.metrics.pool_closed();
-                Ok(Async::Ready(()))
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        match this.poll_forever(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(()) => {
+                this.errors.pool_closed();
+                this.metrics.pool_closed();
+                this.events.close();
+                this.shutdown.closed();
+                Poll::Ready(())
             }
         }
     }
 }
 
 
-impl<V, M> Sink for Pool<V, M>
-    where M: Collect,
-{
-    type SinkItem=V;
-    type SinkError=QueueError<V>;
+impl<V, M> Pool<V, M> {
+    /// Subscribe to the stream of events of this connection pool
+    ///
+    /// Only events happened after subscription are delivered. Stream ends
+    /// when pool is closed.
+    pub fn events(&self) -> Events {
+        self.events.subscribe()
+    }
+    /// Returns a future which resolves when connection pool is fully closed
+    ///
+    /// This happens when all connections are closed and ``ErrorLog`` is
+    /// notified with ``pool_closed``.
+    pub fn closed(&self) -> Closed {
+        Closed(self.events.clone())
+    }
+}
 
-    fn start_send(&mut self, item: Self::SinkItem)
-        -> StartSend<Self::SinkItem, Self::SinkError>
+impl<V, M: Collect> Pool<V, M> {
+    /// Put item into the queue without waiting, also counts metrics
+    pub(crate) fn try_send(&mut self, item: V) -> Result<(), PoolError<V>> {
+        match self.channel {
+            Channel::Bounded(ref mut tx) => match tx.try_send(item) {
+                Ok(()) => {}
+                Err(e) => {
+                    let full = e.is_full();
+                    let item = e.into_inner();
+                    return Err(if full {
+                        PoolError::QueueFull(item)
+                    } else {
+                        PoolError::Rejected(item)
+                    });
+                }
+            },
+            Channel::Buffer(_) => {
+                // never returns `NotReady` without waiting
+                return self.send_buffer(item, false, None).map(|_| ());
+            }
+            Channel::Fair(_) => {
+                return self.send_fair(item, false, None).map(|_| ());
+            }
+        }
+        self.metrics.request_queued();
+        Ok(())
+    }
+    /// Put item into the queue, if `wait` is true and there is no space
+    /// in the queue the item is returned back
+    fn send_buffer(&mut self, item: V, wait: bool, park: Option<&Waker>)
+        -> Result<AsyncSink<V>, PoolError<V>>
+    {
+        let tx = match self.channel {
+            Channel::Buffer(ref tx) => tx,
+            _ => unreachable!(),
+        };
+        match tx.send(item, park) {
+            Ok(dropped) => {
+                // dropped items are counted as queued then dropped
+                self.metrics.request_queued();
+                if let Some(dropped) = dropped {
+                    tx.dropped(dropped);
+                }
+                Ok(AsyncSink::Ready)
+            }
+            Err(buffer::SendError::Full(item))
+                if wait && tx.policy() == Overflow::Backpressure
+            => {
+                Ok(AsyncSink::NotReady(item))
+            }
+            Err(buffer::SendError::Full(item)) => {
+                Err(PoolError::QueueFull(item))
+            }
+            Err(buffer::SendError::Closed(item)) => {
+                Err(PoolError::Rejected(item))
+            }
+        }
+    }
+    fn send_fair(&mut self, item: V, wait: bool, park: Option<&Waker>)
+        -> Result<AsyncSink<V>, PoolError<V>>
     {
-        match self.channel.start_send(item) {
-            Ok(AsyncSink::Ready) => {
+        let tx = match self.channel {
+            Channel::Fair(ref tx) => tx,
+            _ => unreachable!(),
+        };
+        match tx.send(item, park) {
+            Ok(()) => {
                 self.metrics.request_queued();
                 Ok(AsyncSink::Ready)
             }
-            Ok(AsyncSink::NotReady(item)) => Ok(AsyncSink::NotReady(item)),
-            Err(e) => Err(QueueError(e.into_inner())),
-        }
-    }
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        // TODO(tailhook) turn closed flag into error
-        self.channel.poll_complete()
-        .map_err(|_| {
-            // In fact poll_complete of the channel does nothing for now
-            // Even if this is fixed there is no sense to return error for
-            // it because error contains a value SinkItem and there is no way
-            // to construct a value from nothing
-            unreachable!();
-        })
-    }
-    fn close(&mut self) -> Poll<(), Self::SinkError> {
-        self.channel.close()
-        .map_err(|_| {
-            // In fact close of the channel does nothing for now
-            // Even if this is fixed there is no sense to return error for
-            // it because error contains a value SinkItem and there is no way
-            // to construct a value from nothing
-            unreachable!();
-        })
+            Err(fair::SendError::Full(item)) if wait => {
+                Ok(AsyncSink::NotReady(item))
+            }
+            Err(fair::SendError::Full(item)) => {
+                Err(PoolError::QueueFull(item))
+            }
+            Err(fair::SendError::Closed(item)) => {
+                Err(PoolError::Rejected(item))
+            }
+        }
+    }
+    fn pending(&mut self) -> &mut Option<V> {
+        self.pending.get_mut().expect("pending item is not poisoned")
+    }
+    /// Puts the pending item into the queue, current task is woken up
+    /// when there is a space in the queue
+    fn poll_pending(&mut self, cx: &mut Context)
+        -> Poll<Result<(), PoolError<V>>>
+    {
+        let item = match self.pending().take() {
+            Some(item) => item,
+            None => return Poll::Ready(Ok(())),
+        };
+        let result = match self.channel {
+            Channel::Bounded(_) => unreachable!(),
+            Channel::Buffer(_) => {
+                self.send_buffer(item, true, Some(cx.waker()))
+            }
+            Channel::Fair(_) => self.send_fair(item, true, Some(cx.waker())),
+        };
+        match result {
+            Ok(AsyncSink::Ready) => Poll::Ready(Ok(())),
+            Ok(AsyncSink::NotReady(item)) => {
+                *self.pending() = Some(item);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl<V, M> Sink<V> for Pool<V, M>
+    where M: Collect,
+{
+    type Error = PoolError<V>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), PoolError<V>>>
+    {
+        let this = self.get_mut();
+        match this.channel {
+            Channel::Bounded(ref mut tx) => {
+                tx.poll_ready(cx).map_err(|_| PoolError::Closed)
+            }
+            Channel::Buffer(_) | Channel::Fair(_) => this.poll_pending(cx),
+        }
+    }
+    fn start_send(self: Pin<&mut Self>, item: V)
+        -> Result<(), PoolError<V>>
+    {
+        let this = self.get_mut();
+        let result = match this.channel {
+            // `poll_ready` reserves a slot in the channel for this sender
+            Channel::Bounded(_) => return this.try_send(item),
+            Channel::Buffer(_) => this.send_buffer(item, true, None)?,
+            Channel::Fair(_) => this.send_fair(item, true, None)?,
+        };
+        // there is no space left, so item is put into the queue by
+        // `poll_ready` or `poll_flush`
+        if let AsyncSink::NotReady(item) = result {
+            *this.pending() = Some(item);
+        }
+        Ok(())
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), PoolError<V>>>
+    {
+        let this = self.get_mut();
+        if this.events.is_closed() {
+            // requests accepted before graceful shutdown are still
+            // delivered, so shutdown is only reported when pool is closed
+            return Poll::Ready(Err(match this.events.shutdown_reason() {
+                Some(reason) => PoolError::Shutdown(reason),
+                None => PoolError::Closed,
+            }));
+        }
+        match this.channel {
+            Channel::Bounded(ref mut tx) => {
+                Pin::new(tx).poll_flush(cx).map_err(|_| PoolError::Closed)
+            }
+            Channel::Buffer(_) | Channel::Fair(_) => this.poll_pending(cx),
+        }
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), PoolError<V>>>
+    {
+        let this = self.get_mut();
+        match this.channel {
+            Channel::Bounded(ref mut tx) => {
+                Pin::new(tx).poll_close(cx).map_err(|_| PoolError::Closed)
+            }
+            Channel::Buffer(_) | Channel::Fair(_) => this.poll_pending(cx),
+        }
+    }
+}
+
+impl Future for Closed {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0.poll_closed(cx) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<V> PoolError<V> {
+    /// Return ownership of the rejected message (if any)
+    pub fn into_inner(self) -> Option<V> {
+        match self {
+            PoolError::Rejected(v) | PoolError::QueueFull(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl<V> From<QueueError<V>> for PoolError<V> {
+    fn from(e: QueueError<V>) -> PoolError<V> {
+        PoolError::Rejected(e.0)
+    }
+}
+
+impl<T> fmt::Display for PoolError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Closed | PoolError::Rejected(_) => {
+                f.write_str("connection pool is closed")
+            }
+            PoolError::QueueFull(_) => {
+                f.write_str("connection pool queue is full")
+            }
+            PoolError::Shutdown(ref reason) => {
+                write!(f, "connection pool is shut down: {}", reason)
+            }
+            PoolError::__Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for PoolError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Closed => f.write_str("Closed"),
+            PoolError::Shutdown(ref reason) => {
+                f.debug_tuple("Shutdown").field(reason).finish()
+            }
+            PoolError::Rejected(_) => f.write_str("Rejected(_)"),
+            PoolError::QueueFull(_) => f.write_str("QueueFull(_)"),
+            PoolError::__Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+impl<T> ::std::error::Error for PoolError<T> {
+    fn description(&self) -> &str {
+        "PoolError"
     }
 }
 
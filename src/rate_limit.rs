@@ -0,0 +1,289 @@
+//! Limiting rate of requests forwarded to backends
+//!
+//! This is useful to respect upstream quotas. The limit is pool-wide and
+//! uses a token bucket: every forwarded request takes a token, tokens are
+//! refilled at a constant rate up to the burst size. When there are no
+//! tokens, requests wait in the queue:
+//!
+//! ```rust,ignore
+//! let (mut pool, shutdown) = pool_for(connector)
+//!     .connect_to(address_stream)
+//!     .lazy_uniform_connections(2)
+//!     .rate_limit(RateLimit::per_second(100).burst(10))
+//!     .spawn_on(&Handle::current());
+//! ```
+//!
+//! Time requests wait for a token is reported with
+//! ``Collect::request_throttled``.
+use std::fmt;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+
+use config::private::{AsyncSink, Done, Mux};
+use metrics::Collect;
+use timer::{SharedTimer, Sleep};
+
+
+/// Configuration of the rate limit
+///
+/// See ``PoolConfig::rate_limit``.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    rate: f64,
+    burst: u32,
+}
+
+/// A sink which forwards requests to the multiplexer at a limited rate
+pub(crate) struct Throttle<S, M> {
+    sink: S,
+    limit: Option<RateLimit>,
+    tokens: f64,
+    refilled: Instant,
+    throttled: Option<Instant>,
+    sleep: Option<Sleep>,
+    timer: SharedTimer,
+    metrics: M,
+}
+
+impl RateLimit {
+    /// Allow `requests` per second, burst size is one request
+    ///
+    /// # Panics
+    ///
+    /// Panics if `requests` is zero.
+    pub fn per_second(requests: u32) -> RateLimit {
+        RateLimit::new(requests, Duration::from_secs(1))
+    }
+    /// Allow `requests` per specified `period`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `requests` or `period` is zero.
+    pub fn new(requests: u32, period: Duration) -> RateLimit {
+        assert!(requests > 0, "rate limit must be positive");
+        let secs = period.as_secs() as f64 +
+            period.subsec_nanos() as f64 / 1e9;
+        assert!(secs > 0., "rate limit period must be positive");
+        RateLimit {
+            rate: requests as f64 / secs,
+            burst: 1,
+        }
+    }
+    /// Allow up to `requests` to be sent at once after a period of idleness
+    ///
+    /// # Panics
+    ///
+    /// Panics if `requests` is zero.
+    pub fn burst(self, requests: u32) -> RateLimit {
+        assert!(requests > 0, "burst size must be positive");
+        RateLimit { burst: requests, ..self }
+    }
+}
+
+impl<S, M> Throttle<S, M> {
+    pub fn new(sink: S, limit: Option<RateLimit>, timer: &SharedTimer,
+        metrics: M)
+        -> Throttle<S, M>
+    {
+        Throttle {
+            sink,
+            tokens: limit.as_ref().map(|l| l.burst as f64).unwrap_or(0.),
+            limit,
+            refilled: Instant::now(),
+            throttled: None,
+            sleep: None,
+            timer: timer.clone(),
+            metrics,
+        }
+    }
+    /// Takes a token, or returns the time when the next one is available
+    fn take_token(&mut self, limit: &RateLimit) -> Result<(), Instant> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled);
+        let elapsed = elapsed.as_secs() as f64 +
+            elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * limit.rate)
+            .min(limit.burst as f64);
+        self.refilled = now;
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            Ok(())
+        } else {
+            let wait = (1. - self.tokens) / limit.rate;
+            Err(now + Duration::new(wait as u64,
+                (wait.fract() * 1e9) as u32))
+        }
+    }
+}
+
+impl<S, I, M> Mux<I> for Throttle<S, M>
+    where S: Mux<I>,
+          M: Collect,
+{
+    fn start_send(&mut self, cx: &mut Context, item: I)
+        -> Result<AsyncSink<I>, Done>
+    {
+        let limit = match self.limit.take() {
+            Some(limit) => limit,
+            None => return self.sink.start_send(cx, item),
+        };
+        let result = self.start_limited(cx, item, &limit);
+        self.limit = Some(limit);
+        result
+    }
+    fn poll_complete(&mut self, cx: &mut Context) -> Poll<Result<(), Done>> {
+        self.sink.poll_complete(cx)
+    }
+    fn close(&mut self, cx: &mut Context) -> Poll<Result<(), Done>> {
+        self.sink.close(cx)
+    }
+}
+
+impl<S, M> Throttle<S, M>
+    where M: Collect,
+{
+    fn start_limited<I>(&mut self, cx: &mut Context, item: I,
+        limit: &RateLimit)
+        -> Result<AsyncSink<I>, Done>
+        where S: Mux<I>,
+    {
+        if let Some(mut sleep) = self.sleep.take() {
+            if sleep.poll_unpin(cx).is_pending() {
+                self.sleep = Some(sleep);
+                return self.throttled(cx, item);
+            }
+        }
+        loop {
+            match self.take_token(limit) {
+                Ok(()) => break,
+                Err(deadline) => {
+                    if self.throttled.is_none() {
+                        self.throttled = Some(Instant::now());
+                    }
+                    let mut sleep = self.timer.sleep_until(deadline);
+                    match sleep.poll_unpin(cx) {
+                        // rounding of the deadline, retry
+                        Poll::Ready(()) => continue,
+                        Poll::Pending => {
+                            self.sleep = Some(sleep);
+                            return self.throttled(cx, item);
+                        }
+                    }
+                }
+            }
+        }
+        match self.sink.start_send(cx, item)? {
+            AsyncSink::Ready => {
+                if let Some(since) = self.throttled.take() {
+                    self.metrics.request_throttled(since.elapsed());
+                }
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(item) => {
+                // request is not sent, so return the token
+                self.tokens += 1.;
+                Ok(AsyncSink::NotReady(item))
+            }
+        }
+    }
+    /// Returns request back while waiting for a token
+    ///
+    /// The caller doesn't call `poll_complete` while request is not
+    /// accepted, so the inner sink is flushed here to send requests
+    /// which are already accepted.
+    fn throttled<I>(&mut self, cx: &mut Context, item: I)
+        -> Result<AsyncSink<I>, Done>
+        where S: Mux<I>,
+    {
+        if let Poll::Ready(Err(e)) = self.sink.poll_complete(cx) {
+            return Err(e);
+        }
+        Ok(AsyncSink::NotReady(item))
+    }
+}
+
+impl<S, M> fmt::Debug for Throttle<S, M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Throttle")
+            .field("limit", &self.limit)
+            .field("tokens", &self.tokens)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Instant;
+    use futures::future::pending;
+    use futures::task::noop_waker_ref;
+    use config::private::{AsyncSink, Done, Mux};
+    use timer::{SharedTimer, Timer, Sleep};
+    use metrics::Noop;
+    use super::{RateLimit, Throttle};
+
+    struct NoTimer;
+
+    /// Sink which sends accepted request only on `poll_complete`
+    #[derive(Default)]
+    struct Buffered {
+        buffer: Option<u32>,
+        sent: Vec<u32>,
+    }
+
+    impl Mux<u32> for Buffered {
+        fn start_send(&mut self, _cx: &mut Context, item: u32)
+            -> Result<AsyncSink<u32>, Done>
+        {
+            if self.buffer.is_some() {
+                return Ok(AsyncSink::NotReady(item));
+            }
+            self.buffer = Some(item);
+            Ok(AsyncSink::Ready)
+        }
+        fn poll_complete(&mut self, _cx: &mut Context)
+            -> Poll<Result<(), Done>>
+        {
+            self.sent.extend(self.buffer.take());
+            Poll::Ready(Ok(()))
+        }
+        fn close(&mut self, _cx: &mut Context) -> Poll<Result<(), Done>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Timer for NoTimer {
+        fn sleep_until(&self, _deadline: Instant) -> Sleep {
+            Box::pin(pending())
+        }
+    }
+
+    #[test]
+    fn burst() {
+        let timer: SharedTimer = Arc::new(NoTimer);
+        let limit = RateLimit::per_second(1).burst(3);
+        let mut throttle = Throttle::new((), Some(limit.clone()),
+            &timer, Noop);
+        for _ in 0..3 {
+            assert!(throttle.take_token(&limit).is_ok());
+        }
+        let next = throttle.take_token(&limit).unwrap_err();
+        assert!(next > Instant::now());
+    }
+
+    #[test]
+    fn flush_while_throttled() {
+        let timer: SharedTimer = Arc::new(NoTimer);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut throttle = Throttle::new(Buffered::default(),
+            Some(RateLimit::per_second(1)), &timer, Noop);
+        assert_eq!(throttle.start_send(&mut cx, 1).ok(),
+            Some(AsyncSink::Ready));
+        assert_eq!(throttle.start_send(&mut cx, 2).ok(),
+            Some(AsyncSink::NotReady(2)));
+        assert_eq!(throttle.sink.sent, vec![1]);
+    }
+}
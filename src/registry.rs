@@ -0,0 +1,378 @@
+//! A registry of pools, one per service name
+//!
+//! ``PoolRegistry`` creates a pool for a key (usually name and port) when
+//! it's first used, and closes it when it's not used for some time. It's
+//! a `Sink` of `(key, item)` pairs routing each item to the pool of its
+//! key.
+//!
+//! Pools are created from a template config, the address stream of the
+//! template is replaced by the one returned for the key:
+//!
+//! ```rust,ignore
+//! let template = pool_for(connector)
+//!     .connect_to_static(&[])
+//!     .lazy_uniform_connections(2);
+//! let mut registry = PoolRegistry::new(&Handle::current(), template,
+//!         |key: &Key| resolve(key))
+//!     .idle_timeout(Duration::from_secs(300));
+//! registry.send((key, request)).await?;
+//! ```
+//!
+//! Idle pools are shut down gracefully only while the registry is used as
+//! a sink (i.e. it's polled), or when ``PoolRegistry::evict_idle`` is
+//! called. A closed pool is replaced with a new one on the next send,
+//! errors of a pool which is alive (e.g. ``PoolError::QueueFull``) are
+//! returned to the caller along with the key.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::{FutureExt, Sink, Stream};
+use tokio::runtime::Handle;
+
+use address::AddressSet;
+use config::{PoolConfig, NewMux, NewMetrics, NewErrorLog, private};
+use connect::Connect;
+use metrics::Collect;
+use queue::{Pool, PoolError};
+use shutdown::ShutdownHandle;
+use timer::{SharedTimer, Sleep};
+
+
+type Factory<K, V, M> =
+    Box<dyn FnMut(&K) -> (Pool<V, M>, ShutdownHandle) + Send>;
+
+/// A registry of lazily created pools
+pub struct PoolRegistry<K, V, M> {
+    factory: Factory<K, V, M>,
+    pools: HashMap<K, Entry<V, M>>,
+    idle_timeout: Duration,
+    shutdown_timeout: Duration,
+    timer: SharedTimer,
+    timeout: Option<Sleep>,
+    /// Item accepted by `start_send` and not yet put into its pool
+    pending: Option<(K, V)>,
+}
+
+struct Entry<V, M> {
+    pool: Pool<V, M>,
+    shutdown: ShutdownHandle,
+    last_used: Instant,
+}
+
+impl<K, V, M> PoolRegistry<K, V, M>
+    where K: Hash + Eq + Clone,
+          M: Collect,
+{
+    /// Create a registry
+    ///
+    /// Pools are spawned on the runtime specified by handle. Each one is
+    /// a clone of the `template` connecting to the address stream returned
+    /// by `addresses` for its key. Default idle timeout is 60 s.
+    pub fn new<C, T, X, Q, E, NM, A, G>(handle: &Handle,
+        template: PoolConfig<C, T, X, Q, E, NM>, addresses: G)
+        -> PoolRegistry<K, V, M>
+        where PoolConfig<C, T, X, Q, E, NM>: Clone + Send + 'static,
+              G: Fn(&K) -> A + Send + 'static,
+              A: Stream + Send + 'static,
+              A::Item: AddressSet<Addr=<C as Connect>::Address>,
+              C: Connect + 'static,
+              C::Connection: Sink<V>,
+              NM: NewMetrics<Collect=M>,
+              M: 'static,
+              X: NewMux<V, A, C, E::ErrorLog, M>,
+              <X as private::NewMux<V, A, C, E::ErrorLog, M>>::Sink:
+                Send + 'static,
+              E: NewErrorLog<
+                C::Error,
+                <C::Connection as Sink<V>>::Error,
+                <C as Connect>::Address,
+              >,
+              E::ErrorLog: Clone + Send + 'static,
+              Q: private::NewQueue<V, M, Pool=Pool<V, M>>,
+    {
+        let spawn_handle = handle.clone();
+        PoolRegistry {
+            factory: Box::new(move |key| {
+                template.clone()
+                    .connect_to(addresses(key))
+                    .spawn_on(&spawn_handle)
+            }),
+            pools: HashMap::new(),
+            idle_timeout: Duration::from_secs(60),
+            shutdown_timeout: Duration::from_secs(10),
+            timer: Arc::new(handle.clone()),
+            timeout: None,
+            pending: None,
+        }
+    }
+    /// Set time after which unused pool is shut down
+    ///
+    /// Requests which are already queued in the pool are still processed
+    /// (see ``ShutdownHandle::graceful``).
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+    /// Set timeout of graceful shutdown of idle pools
+    ///
+    /// Default is 10 s.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+    /// Get a pool for the key, creating it if needed
+    ///
+    /// This marks the pool as used.
+    pub fn pool(&mut self, key: &K) -> &mut Pool<V, M> {
+        let factory = &mut self.factory;
+        let entry = self.pools.entry(key.clone())
+            .or_insert_with(|| {
+                let (pool, shutdown) = factory(key);
+                Entry {
+                    pool, shutdown,
+                    last_used: Instant::now(),
+                }
+            });
+        entry.last_used = Instant::now();
+        &mut entry.pool
+    }
+    /// Number of currently open pools
+    pub fn len(&self) -> usize {
+        self.pools.len()
+    }
+    /// Returns true if there are no open pools
+    pub fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+    /// Shut down pools which were not used for the idle timeout
+    pub fn evict_idle(&mut self) {
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout;
+        let shutdown_timeout = self.shutdown_timeout;
+        self.pools.retain(|_, e| {
+            if now.duration_since(e.last_used) < idle_timeout {
+                return true;
+            }
+            // the future only tells when pool is closed
+            drop(e.shutdown.graceful(shutdown_timeout));
+            false
+        });
+    }
+    fn poll_timeout(&mut self, cx: &mut Context) {
+        loop {
+            if let Some(ref mut timeout) = self.timeout {
+                if timeout.poll_unpin(cx).is_pending() {
+                    return;
+                }
+            }
+            self.timeout = None;
+            self.evict_idle();
+            let oldest = self.pools.values().map(|e| e.last_used).min();
+            match oldest {
+                Some(time) => {
+                    let deadline = time + self.idle_timeout;
+                    self.timeout = Some(self.timer.sleep_until(deadline));
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+impl<K, V, M> PoolRegistry<K, V, M>
+    where K: Hash + Eq + Clone,
+          M: Collect,
+{
+    /// Puts the pending item into the pool of its key
+    fn poll_pending(&mut self, cx: &mut Context)
+        -> Poll<Result<(), PoolError<(K, V)>>>
+    {
+        let (key, mut item) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Poll::Ready(Ok(())),
+        };
+        // if pool is closed, we replace it with a new one and try again
+        for _ in 0..2 {
+            let pool = self.pool(&key);
+            let result = match Pin::new(&mut *pool).poll_ready(cx) {
+                Poll::Ready(Ok(())) => Pin::new(pool).start_send(item),
+                Poll::Ready(Err(_)) => Err(PoolError::Rejected(item)),
+                Poll::Pending => {
+                    self.pending = Some((key, item));
+                    return Poll::Pending;
+                }
+            };
+            match result {
+                Ok(()) => return Poll::Ready(Ok(())),
+                Err(PoolError::Rejected(v)) => {
+                    self.pools.remove(&key);
+                    item = v;
+                }
+                Err(PoolError::QueueFull(v)) => {
+                    return Poll::Ready(Err(PoolError::QueueFull((key, v))));
+                }
+                Err(_) => unreachable!("start_send only rejects items"),
+            }
+        }
+        Poll::Ready(Err(PoolError::Rejected((key, item))))
+    }
+}
+
+// fields are never pinned
+impl<K, V, M> Unpin for PoolRegistry<K, V, M> {}
+
+impl<K, V, M> Sink<(K, V)> for PoolRegistry<K, V, M>
+    where K: Hash + Eq + Clone,
+          M: Collect,
+{
+    type Error = PoolError<(K, V)>;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), PoolError<(K, V)>>>
+    {
+        let this = self.get_mut();
+        this.poll_timeout(cx);
+        this.poll_pending(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: (K, V))
+        -> Result<(), PoolError<(K, V)>>
+    {
+        // item is put into the pool by the next `poll_ready` or
+        // `poll_flush`, as the pool needs a task context for that
+        let this = self.get_mut();
+        assert!(this.pending.is_none(), "poll_ready must be called first");
+        this.pending = Some(item);
+        Ok(())
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), PoolError<(K, V)>>>
+    {
+        let this = self.get_mut();
+        this.poll_timeout(cx);
+        // item is in the pool's queue as soon as it's not pending, and
+        // closed pools are replaced on the next send
+        this.poll_pending(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Result<(), PoolError<(K, V)>>>
+    {
+        let this = self.get_mut();
+        if let Err(e) = futures::ready!(this.poll_pending(cx)) {
+            return Poll::Ready(Err(e));
+        }
+        this.timeout = None;
+        for (_, entry) in this.pools.drain() {
+            drop(entry.shutdown.graceful(this.shutdown_timeout));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use futures::{Sink, SinkExt};
+    use futures::executor::block_on;
+    use futures::future::{ready, Ready};
+    use futures::task::noop_waker_ref;
+    use tokio::runtime::Builder;
+
+    use address::StaticAddress;
+    use pool_for;
+    use queue::{Overflow, PoolError};
+    use super::PoolRegistry;
+
+    struct Ignore;
+
+    impl Sink<()> for Ignore {
+        type Error = io::Error;
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+        fn start_send(self: Pin<&mut Self>, _item: ())
+            -> Result<(), io::Error>
+        {
+            Ok(())
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn ignore(_: SocketAddr) -> Ready<Result<Ignore, io::Error>> {
+        ready(Ok(Ignore))
+    }
+
+    #[test]
+    fn evict() {
+        // pools are only run inside `block_on`
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let keys = created.clone();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let template = pool_for(ignore)
+            .connect_to_static(&[])
+            .lazy_uniform_connections(1);
+        let mut registry = PoolRegistry::new(rt.handle(), template,
+            move |key: &u32| {
+                keys.lock().unwrap().push(*key);
+                StaticAddress::new(vec![addr])
+            });
+        block_on(registry.send((1, ()))).ok().unwrap();
+        block_on(registry.send((2, ()))).ok().unwrap();
+        block_on(registry.send((1, ()))).ok().unwrap();
+        assert_eq!(registry.len(), 2);
+        let pool = registry.pool(&1).clone();
+        registry = registry.idle_timeout(Duration::new(0, 0));
+        registry.evict_idle();
+        assert!(registry.is_empty());
+        // pool is shut down even though it's still referenced
+        rt.block_on(pool.closed());
+        assert_eq!(*created.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn queue_full() {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let keys = created.clone();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let template = pool_for(ignore)
+            .connect_to_static(&[])
+            .lazy_uniform_connections(1)
+            .with_queue(1, Overflow::Reject);
+        let mut registry = PoolRegistry::new(rt.handle(), template,
+            move |key: &u32| {
+                keys.lock().unwrap().push(*key);
+                StaticAddress::new(vec![addr])
+            });
+        // runtime isn't running, so the queue isn't drained
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(block_on(registry.feed((1, ()))).is_ok());
+        assert!(registry.poll_ready_unpin(&mut cx).is_ready());
+        registry.start_send_unpin((1, ())).ok().unwrap();
+        match registry.poll_flush_unpin(&mut cx) {
+            Poll::Ready(Err(PoolError::QueueFull((1, ())))) => {}
+            _ => panic!("queue full expected"),
+        }
+        assert_eq!(registry.len(), 1);
+        assert_eq!(*created.lock().unwrap(), vec![1]);
+    }
+}
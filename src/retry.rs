@@ -0,0 +1,172 @@
+//! Retrying requests lost on broken connections
+//!
+//! By default a request which was sent to a connection that failed before
+//! flushing it, is lost. With ``PoolConfig::retry`` the pool keeps a copy
+//! of every request until the connection sink reports it's flushed (i.e.
+//! `poll_complete` returns `Ready`) and dispatches the copy to another
+//! connection if the sink fails.
+//!
+//! Pipelined sinks might never report that everything is flushed, so only
+//! a limited number of the most recent copies is kept per connection (see
+//! ``Retry::max_unflushed``).
+//!
+//! Only requests marked as ``Retryable`` are retried. Requests that were
+//! handed to a connection which closed before even starting to send them
+//! are always re-dispatched, regardless of this policy.
+//!
+//! ```rust,ignore
+//! impl Retryable for Request {
+//!     fn retry_copy(&self) -> Option<Request> {
+//!         if self.is_idempotent() { Some(self.clone()) } else { None }
+//!     }
+//! }
+//!
+//! let (pool, shutdown) = pool_for(connector)
+//!     .connect_to(address_stream)
+//!     .lazy_uniform_connections(2)
+//!     .retry(Retry::new().max_attempts(3))
+//!     .spawn_on(&Handle::current());
+//! ```
+
+use config::private::RetryPolicy;
+
+
+/// A request that might be sent again if connection fails
+pub trait Retryable: Sized {
+    /// Returns a copy of the request if it's safe to send it again
+    ///
+    /// Return `None` for non-idempotent requests.
+    fn retry_copy(&self) -> Option<Self>;
+}
+
+/// A retry policy used by ``PoolConfig::retry``
+#[derive(Debug, Clone)]
+pub struct Retry {
+    pub(crate) max_attempts: u32,
+    pub(crate) max_unflushed: usize,
+    pub(crate) reserve: f64,
+    pub(crate) ratio: f64,
+}
+
+/// A policy which never retries requests (default)
+#[derive(Debug, Clone)]
+pub struct NoRetry;
+
+/// Budget limiting the number of retries (also used for hedged requests)
+#[derive(Debug)]
+pub(crate) struct Budget {
+    balance: f64,
+    reserve: f64,
+    ratio: f64,
+}
+
+impl Retry {
+    /// Create a default retry policy
+    ///
+    /// By default request is sent at most 3 times, and retry budget
+    /// allows 10 retries in a burst and one retry per 10 requests
+    /// sustained. At most 100 unflushed requests are kept per connection.
+    pub fn new() -> Retry {
+        Retry {
+            max_attempts: 3,
+            max_unflushed: 100,
+            reserve: 10.,
+            ratio: 0.1,
+        }
+    }
+    /// Set maximum number of times a single request is sent (including
+    /// the first attempt)
+    pub fn max_attempts(mut self, num: u32) -> Retry {
+        assert!(num > 0);
+        self.max_attempts = num;
+        self
+    }
+    /// Set maximum number of request copies kept per connection
+    ///
+    /// When the limit is reached the oldest copy is dropped, i.e. that
+    /// request is considered delivered and will not be retried.
+    pub fn max_unflushed(mut self, num: usize) -> Retry {
+        self.max_unflushed = num;
+        self
+    }
+    /// Set retry budget
+    ///
+    /// Every new request adds `ratio` to the budget, every retry takes
+    /// one. Budget can't grow above `reserve` (which is also its initial
+    /// value), so `reserve` is a number of retries allowed in a burst.
+    /// Retry budget prevents retry storms when all hosts are failing.
+    pub fn budget(mut self, reserve: u32, ratio: f64) -> Retry {
+        assert!(ratio >= 0.);
+        self.reserve = reserve as f64;
+        self.ratio = ratio;
+        self
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry::new()
+    }
+}
+
+impl<I> RetryPolicy<I> for NoRetry {
+    fn settings(&self) -> Option<&Retry> {
+        None
+    }
+    fn copy(&self, _item: &I) -> Option<I> {
+        None
+    }
+}
+
+impl<I: Retryable> RetryPolicy<I> for Retry {
+    fn settings(&self) -> Option<&Retry> {
+        Some(self)
+    }
+    fn copy(&self, item: &I) -> Option<I> {
+        item.retry_copy()
+    }
+}
+
+impl Budget {
+    pub fn new(reserve: f64, ratio: f64) -> Budget {
+        Budget {
+            balance: reserve,
+            reserve,
+            ratio,
+        }
+    }
+    pub fn deposit(&mut self) {
+        self.balance = (self.balance + self.ratio).min(self.reserve);
+    }
+    pub fn withdraw(&mut self) -> bool {
+        if self.balance >= 1. {
+            self.balance -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Budget;
+
+    #[test]
+    fn budget() {
+        let mut b = Budget::new(2., 0.5);
+        assert!(b.withdraw());
+        assert!(b.withdraw());
+        assert!(!b.withdraw());
+        b.deposit();
+        assert!(!b.withdraw());
+        b.deposit();
+        assert!(b.withdraw());
+        for _ in 0..10 {
+            b.deposit();
+        }
+        assert!(b.withdraw());
+        assert!(b.withdraw());
+        assert!(!b.withdraw());
+    }
+}
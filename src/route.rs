@@ -0,0 +1,157 @@
+//! Per-request routing hints
+//!
+//! By default the pool doesn't look into requests, and sends them to any
+//! connection which is ready. With ``PoolConfig::routing`` requests
+//! implementing ``Route`` can be pinned to a specific host (e.g. to finish
+//! a multi-step transaction) or avoid some hosts, and get to know which
+//! host they are sent to.
+//!
+//! Hints are honoured while respecting connection limits, i.e. a request
+//! excluding a host waits for a connection to another host even if
+//! connections to the excluded one are idle. If it waits longer than
+//! ``uniform::Config::park_timeout`` it's treated like a request lost on a
+//! broken connection (see ``retry``).
+//!
+//! ``Hint`` might be embedded into a request to implement ``Route`` by
+//! delegation, ``call::Call`` has it built in:
+//!
+//! ```rust,ignore
+//! let (call, response) = Call::new(request);
+//! pool.start_send(call.with_hint(Hint::Pin(addr)));
+//! ```
+use std::net::SocketAddr;
+
+use address::PoolAddress;
+use config::private::RoutePolicy;
+
+
+/// A request which has a routing hint
+///
+/// Type parameter is the address type of the pool (see ``PoolAddress``).
+pub trait Route<P=SocketAddr> {
+    /// Returns an address request must be sent to
+    ///
+    /// Request is dropped if this address is not in the pool's address
+    /// set (``ErrorLog::unknown_address`` and
+    /// ``Collect::request_dropped`` are called). Requests waiting for a
+    /// connection to their host don't block other requests.
+    fn pinned(&self) -> Option<P> {
+        None
+    }
+    /// Returns true if request should not be sent to this address
+    ///
+    /// If all addresses of the pool are excluded the hint is ignored.
+    fn is_excluded(&self, _addr: &P) -> bool {
+        false
+    }
+    /// Called when request is handed to a connection to this address
+    ///
+    /// Note: this might be called multiple times if connection isn't able
+    /// to accept the request, the last address is the real one. A copy of
+    /// the request for ``retry`` is made after this call, so the copy
+    /// might exclude the host the request was sent to.
+    fn dispatched(&mut self, _addr: &P) {}
+}
+
+/// A routing hint that might be embedded into a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hint<P=SocketAddr> {
+    /// Send to any host
+    Any,
+    /// Send only to this address
+    Pin(P),
+    /// Don't send to these addresses
+    Exclude(Vec<P>),
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// A policy which uses ``Route`` hints of requests
+#[derive(Debug, Clone)]
+pub struct Routing;
+
+/// A policy which ignores routing hints (default)
+#[derive(Debug, Clone)]
+pub struct NoRouting;
+
+impl<P: PoolAddress> Hint<P> {
+    /// Exclude one more address
+    ///
+    /// Pinned address is unpinned if it's excluded.
+    pub fn exclude(&mut self, addr: P) {
+        match *self {
+            Hint::Exclude(ref mut list) => {
+                if !list.contains(&addr) {
+                    list.push(addr);
+                }
+                return;
+            }
+            Hint::Pin(ref pin) if *pin != addr => return,
+            _ => {}
+        }
+        *self = Hint::Exclude(vec![addr]);
+    }
+}
+
+impl<P: PoolAddress> Route<P> for Hint<P> {
+    fn pinned(&self) -> Option<P> {
+        match *self {
+            Hint::Pin(ref addr) => Some(addr.clone()),
+            _ => None,
+        }
+    }
+    fn is_excluded(&self, addr: &P) -> bool {
+        match *self {
+            Hint::Exclude(ref list) => list.contains(addr),
+            _ => false,
+        }
+    }
+}
+
+impl<I, P> RoutePolicy<I, P> for NoRouting {
+    fn enabled(&self) -> bool {
+        false
+    }
+    fn pinned(&self, _item: &I) -> Option<P> {
+        None
+    }
+    fn is_excluded(&self, _item: &I, _addr: &P) -> bool {
+        false
+    }
+    fn dispatched(&self, _item: &mut I, _addr: &P) {}
+}
+
+impl<I: Route<P>, P> RoutePolicy<I, P> for Routing {
+    fn enabled(&self) -> bool {
+        true
+    }
+    fn pinned(&self, item: &I) -> Option<P> {
+        item.pinned()
+    }
+    fn is_excluded(&self, item: &I, addr: &P) -> bool {
+        item.is_excluded(addr)
+    }
+    fn dispatched(&self, item: &mut I, addr: &P) {
+        item.dispatched(addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use super::{Hint, Route};
+
+    #[test]
+    fn exclude() {
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let mut hint = Hint::Pin(a);
+        hint.exclude(b);
+        assert_eq!(hint.pinned(), Some(a));
+        hint.exclude(a);
+        assert_eq!(hint.pinned(), None);
+        assert!(hint.is_excluded(&a));
+        hint.exclude(b);
+        assert_eq!(hint, Hint::Exclude(vec![a, b]));
+    }
+}
@@ -0,0 +1,208 @@
+//! Plain data configuration of the connection pool
+//!
+//! This is useful to load pool configuration from configuration files.
+//! With `serde` feature enabled ``Settings`` can be deserialized, all
+//! fields are optional, durations are in milliseconds:
+//!
+//! ```yaml
+//! mux: lazy-uniform
+//! balancing: round-robin
+//! conn-limit: 2
+//! queue-size: 100
+//! queue-overflow: backpressure
+//! reconnect-min-ms: 50
+//! reconnect-max-ms: 150
+//! fail-fast-timeout-ms: 1000
+//! ```
+//!
+//! Settings are applied using ``PoolConfig::apply_settings``.
+use std::fmt;
+use std::time::Duration;
+
+use queue::Overflow;
+use uniform;
+
+
+/// Plain data configuration of the connection pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(Deserialize))]
+#[cfg_attr(feature="serde",
+    serde(default, rename_all="kebab-case", deny_unknown_fields))]
+pub struct Settings {
+    /// Type of the multiplexer
+    pub mux: Mux,
+    /// Strategy of distributing requests between connections
+    pub balancing: Balancing,
+    /// Number of connections per host
+    pub conn_limit: u32,
+    /// Number of requests queued when no connection can accept them
+    pub queue_size: usize,
+    /// What to do when the queue is full
+    pub queue_overflow: Overflow,
+    /// Minimum time address is blacklisted after connection error
+    pub reconnect_min_ms: u64,
+    /// Maximum time address is blacklisted after connection error
+    pub reconnect_max_ms: u64,
+    /// Time requests wait for a healthy backend before they are failed
+    ///
+    /// Overrides timeout of ``fail_fast::FailFast`` policy, has no effect
+    /// unless pool is configured with ``PoolConfig::fail_fast``.
+    pub fail_fast_timeout_ms: Option<u64>,
+}
+
+/// Type of the multiplexer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(Deserialize))]
+#[cfg_attr(feature="serde", serde(rename_all="kebab-case"))]
+pub enum Mux {
+    /// Uniform connection pool with lazy connections
+    ///
+    /// See ``PoolConfig::lazy_uniform_connections``
+    LazyUniform,
+    #[doc(hidden)]
+    #[cfg_attr(feature="serde", serde(skip_deserializing))]
+    __Nonexhaustive,
+}
+
+/// Strategy of distributing requests between connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature="serde", derive(Deserialize))]
+#[cfg_attr(feature="serde", serde(rename_all="kebab-case"))]
+pub enum Balancing {
+    /// Round-robin until pushback happens
+    RoundRobin,
+    #[doc(hidden)]
+    #[cfg_attr(feature="serde", serde(skip_deserializing))]
+    __Nonexhaustive,
+}
+
+/// Error validating ``Settings``
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Connection limit is zero, so no requests can be sent
+    NoConnections,
+    /// Connection limit is too large
+    TooManyConnections(u32),
+    /// Queue size is zero, so no requests can be sent
+    NoQueue,
+    /// Minimum reconnect time is larger than maximum
+    ReconnectRange(u64, u64),
+    /// Balancing strategy is not supported by the multiplexer
+    UnsupportedBalancing(Mux, Balancing),
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            mux: Mux::LazyUniform,
+            balancing: Balancing::RoundRobin,
+            conn_limit: 2,
+            queue_size: 100,
+            queue_overflow: Overflow::Backpressure,
+            reconnect_min_ms: 50,
+            reconnect_max_ms: 150,
+            fail_fast_timeout_ms: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Check that settings make sense
+    pub fn validate(&self) -> Result<(), Error> {
+        match (self.mux, self.balancing) {
+            (Mux::LazyUniform, Balancing::RoundRobin) => {}
+            (mux, bal) => return Err(Error::UnsupportedBalancing(mux, bal)),
+        }
+        if self.conn_limit == 0 {
+            return Err(Error::NoConnections);
+        }
+        if self.conn_limit == u32::MAX {
+            return Err(Error::TooManyConnections(self.conn_limit));
+        }
+        if self.queue_size == 0 {
+            return Err(Error::NoQueue);
+        }
+        if self.reconnect_min_ms > self.reconnect_max_ms {
+            return Err(Error::ReconnectRange(
+                self.reconnect_min_ms, self.reconnect_max_ms));
+        }
+        Ok(())
+    }
+    /// Runtime configuration of the uniform pool with these settings
+    ///
+    /// This can be used to reconfigure already running pool when settings
+    /// are reloaded (see ``PoolConfig::config_stream``). Note that queue
+    /// size can't be changed at runtime.
+    pub fn uniform_config(&self) -> Result<uniform::Config, Error> {
+        self.validate()?;
+        let config = uniform::Config::new(self.conn_limit)
+            .reconnect_backoff(
+                Duration::from_millis(self.reconnect_min_ms),
+                Duration::from_millis(self.reconnect_max_ms));
+        Ok(match self.fail_fast_timeout_ms {
+            Some(ms) => config.fail_fast_timeout(Duration::from_millis(ms)),
+            None => config,
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match *self {
+            NoConnections => f.write_str("connection limit must be positive"),
+            TooManyConnections(n) => {
+                write!(f, "connection limit {} is too large", n)
+            }
+            NoQueue => f.write_str("queue size must be positive"),
+            ReconnectRange(min, max) => {
+                write!(f, "minimum reconnect time {}ms is larger than \
+                           maximum {}ms", min, max)
+            }
+            UnsupportedBalancing(mux, bal) => {
+                write!(f, "balancing {:?} is not supported by {:?} mux",
+                    bal, mux)
+            }
+            __Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn description(&self) -> &str {
+        "invalid connection pool settings"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use uniform::Config;
+    use super::{Settings, Error};
+
+    #[test]
+    fn validate() {
+        assert_eq!(Settings::default().validate(), Ok(()));
+        assert_eq!(Settings { conn_limit: 0, .. Settings::default() }
+            .validate(), Err(Error::NoConnections));
+        assert_eq!(Settings { queue_size: 0, .. Settings::default() }
+            .validate(), Err(Error::NoQueue));
+        assert_eq!(Settings {
+                reconnect_min_ms: 200,
+                reconnect_max_ms: 100,
+                .. Settings::default()
+            }.validate(), Err(Error::ReconnectRange(200, 100)));
+    }
+
+    #[test]
+    fn timeouts() {
+        let config = Settings {
+            fail_fast_timeout_ms: Some(500),
+            .. Settings::default()
+        }.uniform_config().unwrap();
+        assert_eq!(config, Config::new(2)
+            .fail_fast_timeout(Duration::from_millis(500)));
+    }
+}
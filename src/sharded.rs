@@ -0,0 +1,459 @@
+//! Connection pool sharded across multiple runtimes
+//!
+//! Normally the whole connection pool runs in a single task which may
+//! become a bottleneck under heavy load. With ``PoolConfig::spawn_sharded``
+//! a single ``queue::Pool`` front-end is backed by a uniform pool (shard)
+//! spawned on each of the specified runtimes (usually single-threaded
+//! ones), each shard having its own part of connection limit.
+//!
+//! ```rust,ignore
+//! let (pool, shutdown) = pool_for(connector)
+//!     .connect_to(address_stream)
+//!     .lazy_uniform_connections(8)
+//!     .spawn_sharded(&Handle::current(), &runtimes, Sharding::new());
+//! ```
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::{FutureExt, Sink, Stream, StreamExt};
+use futures::channel::mpsc::{channel, unbounded, Sender};
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
+use tokio::runtime::Handle;
+use void::Void;
+
+use address::AddressSet;
+use config::{PoolConfig, PoolOf, NewQueue, NewMetrics, NewErrorLog};
+use config::private;
+use config::private::{AsyncSink, Done, Mux, NewMux};
+use config::private::{RetryPolicy, RoutePolicy, FailPolicy, LimitPolicy};
+use connect::Connect;
+use error_log::ErrorLog;
+use events::Subscribers;
+use metrics;
+use queue::ForwardFuture;
+use rate_limit::Throttle;
+use shutdown::{Shutdown, ShutdownHandle};
+use timer::SharedTimer;
+use uniform::LazyUniform;
+
+
+/// A policy of splitting connection pool between shards
+#[derive(Debug, Clone)]
+pub struct Sharding {
+    split: Split,
+    steal: bool,
+    queue_size: usize,
+}
+
+/// Defines how per-host connection limit is split between shards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Split {
+    /// Connection limit is divided between shards
+    ///
+    /// Every shard gets at least one connection per host even if
+    /// connection limit is smaller than number of shards.
+    Divide,
+    /// Every shard gets full connection limit
+    Replicate,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// A front-end sink that distributes requests between shards
+struct Shards<A: Stream, I> {
+    address: Option<Pin<Box<A>>>,
+    address_senders: Vec<UnboundedSender<A::Item>>,
+    senders: Vec<Option<Sender<I>>>,
+    done: Vec<Option<oneshot::Receiver<()>>>,
+    next: usize,
+    steal: bool,
+}
+
+/// An error log for shard forwarders, errors are reported by the front-end
+struct Silent;
+
+impl Sharding {
+    /// Create a default policy
+    ///
+    /// By default connection limit is divided between shards, requests are
+    /// distributed round-robin, and if a shard can't accept a request
+    /// the request is sent to the next one (work stealing).
+    pub fn new() -> Sharding {
+        Sharding {
+            split: Split::Divide,
+            steal: true,
+            queue_size: 1,
+        }
+    }
+    /// Set how connection limit is split between shards
+    pub fn split(mut self, split: Split) -> Sharding {
+        self.split = split;
+        self
+    }
+    /// Enable or disable work stealing
+    ///
+    /// When disabled requests are distributed strictly round-robin, so a
+    /// saturated shard blocks the whole pool until it can accept a
+    /// request.
+    pub fn work_stealing(mut self, enable: bool) -> Sharding {
+        self.steal = enable;
+        self
+    }
+    /// Set the size of the queue in front of every shard
+    ///
+    /// Main queue of the pool is in the front-end, so this value should be
+    /// small for the work stealing to be effective. Default is `1`.
+    pub fn shard_queue_size(mut self, num: usize) -> Sharding {
+        self.queue_size = num;
+        self
+    }
+    fn conn_limit(&self, total: u32, shard: usize, shards: usize) -> u32 {
+        match self.split {
+            Split::Divide => {
+                let shards = shards as u32;
+                let shard = shard as u32;
+                let limit = total / shards +
+                    if shard < total % shards { 1 } else { 0 };
+                limit.max(1)
+            }
+            Split::Replicate => total,
+            Split::__Nonexhaustive => unreachable!(),
+        }
+    }
+}
+
+impl Default for Sharding {
+    fn default() -> Sharding {
+        Sharding::new()
+    }
+}
+
+impl<C, A, R, H, F, L, Q, E, M>
+    PoolConfig<C, A, LazyUniform<R, H, F, L>, Q, E, M>
+{
+    /// Spawn a connection pool sharded across multiple runtimes
+    ///
+    /// Front-end of the pool (the queue) and the address stream run on the
+    /// runtime specified by `handle`, each of `runtimes` gets its own
+    /// shard. Error log is constructed for each shard, metrics collector
+    /// is shared between all of them.
+    ///
+    /// ``config_stream`` is not supported for sharded pools. Shutdown
+    /// handle stops the front-end, shards are closed when they've sent
+    /// their requests.
+    pub fn spawn_sharded<I>(self, handle: &Handle, runtimes: &[Handle],
+        sharding: Sharding)
+        -> (PoolOf<I, Q, M>, ShutdownHandle)
+        where A: Stream + Send + 'static,
+              A::Item: AddressSet<Addr=<C as Connect>::Address>,
+              A::Item: Clone + Send,
+              C: Connect + Clone + Send + 'static,
+              C::Future: Send + 'static,
+              C::Connection: Sink<I> + Send + 'static,
+              C::Error: Send + 'static,
+              <C::Connection as Sink<I>>::Error: Send + 'static,
+              I: Send + 'static,
+              M: NewMetrics,
+              M::Collect: Send + 'static,
+              E: NewErrorLog<
+                C::Error,
+                <C::Connection as Sink<I>>::Error,
+                <C as Connect>::Address,
+              >,
+              E: Clone,
+              E::ErrorLog: Clone + Send + 'static,
+              R: RetryPolicy<I> + Clone + Send + 'static,
+              H: RoutePolicy<I, <C as Connect>::Address>,
+              H: Clone + Send + 'static,
+              F: FailPolicy<I> + Clone + Send + 'static,
+              L: LimitPolicy<I> + Clone + Send + 'static,
+              Q: NewQueue<I, <M as NewMetrics>::Collect,
+                Pool=<Q as private::NewQueue<
+                    I,
+                    <M as NewMetrics>::Collect,
+                >>::Pool
+              >,
+    {
+        assert!(!runtimes.is_empty(), "at least one shard is required");
+        assert!(self.mux.updates.is_none(),
+            "config stream is not supported for sharded pools");
+        let m = self.metrics.construct(&self.name);
+        let ev = Subscribers::new();
+        let mut shards = Shards {
+            address: Some(Box::pin(self.address)),
+            address_senders: Vec::new(),
+            senders: Vec::new(),
+            done: Vec::new(),
+            next: 0,
+            steal: sharding.steal,
+        };
+        for (idx, runtime) in runtimes.iter().enumerate() {
+            let (addr_tx, addr_rx) = unbounded();
+            let (tx, rx) = channel(sharding.queue_size.saturating_sub(1));
+            let (done_tx, done_rx) = oneshot::channel();
+            shards.address_senders.push(addr_tx);
+            shards.senders.push(Some(tx));
+            shards.done.push(Some(done_rx));
+
+            let mut config = self.mux.config.clone();
+            config.conn_limit = sharding.conn_limit(
+                config.conn_limit, idx, runtimes.len());
+            let timer: SharedTimer = Arc::new(runtime.clone());
+            let mux = LazyUniform {
+                config,
+                updates: None,
+                retry: self.mux.retry.clone(),
+                route: self.mux.route.clone(),
+                fail: self.mux.fail.clone(),
+                limit: self.mux.limit.clone(),
+            };
+            let lazy = mux.construct(&timer,
+                addr_rx, self.connector.clone(),
+                self.errors.clone().construct(&self.name), m.clone(),
+                ev.clone());
+            let shutdown = Shutdown::new(&ShutdownHandle::new(), &timer);
+            runtime.spawn(ForwardFuture::new(rx.into(), lazy,
+                    metrics::Noop, Silent, Subscribers::new(), shutdown)
+                .map(move |()| {
+                    done_tx.send(()).ok();
+                }));
+        }
+        let e = self.errors.construct(&self.name);
+        let timer: SharedTimer = Arc::new(handle.clone());
+        let shutdown = Shutdown::new(&self.shutdown, &timer);
+        let shards = Throttle::new(shards, self.rate_limit, &timer, m.clone());
+        let (pool, future) = self.queue.build(shards, e, m, ev, shutdown);
+        handle.spawn(future);
+        (pool, self.shutdown)
+    }
+}
+
+impl<A, I> Shards<A, I>
+    where A: Stream,
+          A::Item: Clone,
+{
+    fn forward_addresses(&mut self, cx: &mut Context) {
+        let ended = match self.address {
+            Some(ref mut stream) => loop {
+                match stream.poll_next_unpin(cx) {
+                    Poll::Ready(Some(addr)) => {
+                        for tx in &self.address_senders {
+                            tx.unbounded_send(addr.clone()).ok();
+                        }
+                    }
+                    Poll::Ready(None) => break true,
+                    Poll::Pending => break false,
+                }
+            },
+            None => false,
+        };
+        if ended {
+            // shards shut down when their address streams end
+            self.address = None;
+            self.address_senders.clear();
+        }
+    }
+    /// Returns true if all shards are shut down
+    fn poll_done(&mut self, cx: &mut Context) -> bool {
+        let mut all_done = true;
+        for (done, sender) in self.done.iter_mut().zip(&mut self.senders) {
+            if let Some(mut rx) = done.take() {
+                match rx.poll_unpin(cx) {
+                    Poll::Pending => {
+                        *done = Some(rx);
+                        all_done = false;
+                    }
+                    Poll::Ready(Ok(())) | Poll::Ready(Err(oneshot::Canceled))
+                    => {
+                        *sender = None;
+                    }
+                }
+            }
+        }
+        all_done
+    }
+}
+
+impl<A, I> Mux<I> for Shards<A, I>
+    where A: Stream,
+          A::Item: Clone,
+{
+    fn start_send(&mut self, cx: &mut Context, mut item: I)
+        -> Result<AsyncSink<I>, Done>
+    {
+        self.forward_addresses(cx);
+        let num = self.senders.len();
+        for _ in 0..num {
+            let idx = self.next;
+            self.next = (idx + 1) % num;
+            let result = match self.senders[idx] {
+                Some(ref mut tx) => match tx.poll_ready(cx) {
+                    Poll::Ready(Ok(())) => match tx.try_send(item) {
+                        Ok(()) => Ok(AsyncSink::Ready),
+                        Err(ref e) if e.is_full() => {
+                            unreachable!("slot is reserved by poll_ready");
+                        }
+                        Err(e) => Err(e.into_inner()),
+                    },
+                    Poll::Pending => Ok(AsyncSink::NotReady(item)),
+                    Poll::Ready(Err(_)) => Err(item),
+                },
+                None => continue,
+            };
+            match result {
+                Ok(AsyncSink::Ready) => return Ok(AsyncSink::Ready),
+                Ok(AsyncSink::NotReady(value)) => {
+                    item = value;
+                    if !self.steal {
+                        // wait for this very shard
+                        self.next = idx;
+                        return Ok(AsyncSink::NotReady(item));
+                    }
+                }
+                Err(value) => {
+                    self.senders[idx] = None;
+                    item = value;
+                }
+            }
+        }
+        if self.senders.iter().all(|s| s.is_none()) {
+            return Err(Done);
+        }
+        Ok(AsyncSink::NotReady(item))
+    }
+    fn poll_complete(&mut self, cx: &mut Context) -> Poll<Result<(), Done>> {
+        self.forward_addresses(cx);
+        if self.poll_done(cx) {
+            return Poll::Ready(Err(Done));
+        }
+        Poll::Pending
+    }
+    fn close(&mut self, cx: &mut Context) -> Poll<Result<(), Done>> {
+        // dropping senders makes shards shut down
+        for sender in &mut self.senders {
+            *sender = None;
+        }
+        if self.poll_done(cx) {
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+}
+
+impl ErrorLog for Silent {
+    type ConnectionError = Void;
+    type SinkError = Void;
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::task::{Context, Poll};
+    use std::thread::{self, ThreadId};
+    use std::time::Duration;
+
+    use futures::{Sink, SinkExt};
+    use futures::channel::oneshot;
+    use futures::future::ready;
+    use futures::stream::iter;
+    use tokio::runtime::Builder;
+
+    use pool_for;
+    use super::{Sharding, Split};
+
+    /// Connection which records thread it's running on
+    struct Record(Arc<Mutex<Vec<ThreadId>>>);
+
+    impl Sink<u32> for Record {
+        type Error = io::Error;
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+        fn start_send(self: Pin<&mut Self>, _item: u32)
+            -> Result<(), io::Error>
+        {
+            self.0.lock().unwrap().push(thread::current().id());
+            Ok(())
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn divide() {
+        let s = Sharding::new();
+        assert_eq!((0..3).map(|i| s.conn_limit(8, i, 3)).collect::<Vec<_>>(),
+                   vec![3, 3, 2]);
+        assert_eq!((0..4).map(|i| s.conn_limit(2, i, 4)).collect::<Vec<_>>(),
+                   vec![1, 1, 1, 1]);
+        let s = Sharding::new().split(Split::Replicate);
+        assert_eq!((0..3).map(|i| s.conn_limit(8, i, 3)).collect::<Vec<_>>(),
+                   vec![8, 8, 8]);
+    }
+
+    #[test]
+    fn spread() {
+        let mut runtimes = Vec::new();
+        let mut stops = Vec::new();
+        let mut threads = Vec::new();
+        for _ in 0..2 {
+            let (tx, rx) = mpsc::channel();
+            let (stop_tx, stop_rx) = oneshot::channel::<()>();
+            threads.push(thread::spawn(move || {
+                let rt = Builder::new_current_thread()
+                    .enable_all().build().unwrap();
+                tx.send(rt.handle().clone()).unwrap();
+                rt.block_on(stop_rx).ok();
+            }));
+            runtimes.push(rx.recv().unwrap());
+            stops.push(stop_tx);
+        }
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let conn = seen.clone();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let (mut pool, shutdown) = pool_for(move |_: SocketAddr| {
+                ready(Ok::<_, io::Error>(Record(conn.clone())))
+            })
+            .connect_to_static(&[addr])
+            .lazy_uniform_connections(2)
+            .spawn_sharded(rt.handle(), &runtimes,
+                Sharding::new().work_stealing(false));
+        let closed = pool.closed();
+        let sent = rt.block_on(pool.send_all(&mut iter((0..10).map(Ok))));
+        assert!(sent.is_ok());
+        rt.block_on(shutdown.graceful(Duration::from_secs(5)));
+        rt.block_on(closed);
+
+        let mut per_thread = HashMap::new();
+        for id in seen.lock().unwrap().iter() {
+            *per_thread.entry(*id).or_insert(0) += 1;
+        }
+        assert_eq!(per_thread.len(), 2);
+        assert!(per_thread.values().all(|&n| n == 5));
+        assert!(!per_thread.contains_key(&thread::current().id()));
+
+        for stop in stops {
+            stop.send(()).unwrap();
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
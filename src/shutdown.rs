@@ -0,0 +1,206 @@
+//! Explicit shutdown of the connection pool
+//!
+//! Without a ``ShutdownHandle`` pool works until all ``queue::Pool``
+//! handles are dropped or the address stream is closed. The handle is
+//! returned from ``PoolConfig::spawn_on`` (or ``PoolConfig::shutdown_handle``
+//! if pool is built manually):
+//!
+//! ```rust,ignore
+//! let (pool, shutdown) = pool_for(connector)
+//!     .connect_to_static(&addrs)
+//!     .lazy_uniform_connections(2)
+//!     .spawn_on(&Handle::current());
+//! // ...
+//! shutdown.graceful(Duration::from_secs(5)).await;
+//! ```
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use futures::future::Shared;
+use futures::channel::oneshot;
+use futures::task::AtomicWaker;
+
+
+/// A handle that shuts down the connection pool
+///
+/// The handle can be cloned and sent to another thread, dropping it
+/// doesn't affect the pool.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+    closed: Shared<oneshot::Receiver<()>>,
+}
+
+/// A future returned by ``ShutdownHandle`` methods
+///
+/// Resolves when pool is fully closed (i.e. when ``ErrorLog::pool_closed``
+/// is called).
+#[must_use = "futures do nothing unless polled"]
+pub struct ShutdownFuture(Shared<oneshot::Receiver<()>>);
+
+pub(crate) use self::shared::{Shutdown, Mode};
+use self::shared::Inner;
+
+mod shared {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use std::task::Context;
+    use std::time::Instant;
+
+    use futures::FutureExt;
+    use futures::channel::oneshot;
+    use futures::task::AtomicWaker;
+
+    use shutdown::ShutdownHandle;
+    use timer::{SharedTimer, Sleep};
+
+    /// A kind of shutdown requested
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Mode {
+        Running,
+        Graceful(Instant),
+        Immediate,
+    }
+
+    pub struct Inner {
+        pub mode: Mutex<Mode>,
+        pub task: AtomicWaker,
+        pub closed: Mutex<Option<oneshot::Sender<()>>>,
+    }
+
+    /// Pool side of the shutdown handle
+    pub struct Shutdown {
+        inner: Arc<Inner>,
+        timer: SharedTimer,
+        timeout: Option<Sleep>,
+        closed: Option<oneshot::Sender<()>>,
+    }
+
+    impl Shutdown {
+        pub fn new(handle: &ShutdownHandle, timer: &SharedTimer)
+            -> Shutdown
+        {
+            Shutdown {
+                inner: handle.inner.clone(),
+                timer: timer.clone(),
+                timeout: None,
+                closed: handle.inner.closed.lock()
+                    .expect("shutdown sender is not poisoned")
+                    .take(),
+            }
+        }
+        /// Returns requested shutdown mode, graceful shutdown turns into
+        /// immediate one when its deadline passes
+        pub fn poll(&mut self, cx: &mut Context) -> Mode {
+            self.inner.task.register(cx.waker());
+            let mode = *self.inner.mode.lock()
+                .expect("shutdown mode is not poisoned");
+            match mode {
+                Mode::Graceful(deadline) => {
+                    let timer = &self.timer;
+                    let timeout = self.timeout.get_or_insert_with(|| {
+                        timer.sleep_until(deadline)
+                    });
+                    if timeout.poll_unpin(cx).is_ready() {
+                        Mode::Immediate
+                    } else {
+                        mode
+                    }
+                }
+                mode => mode,
+            }
+        }
+        /// Notifies futures returned by ``ShutdownHandle`` that pool is
+        /// closed
+        pub fn closed(&mut self) {
+            if let Some(tx) = self.closed.take() {
+                tx.send(()).ok();
+            }
+        }
+    }
+
+    impl fmt::Debug for Shutdown {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("Shutdown")
+                .field("mode", &*self.inner.mode.lock()
+                    .expect("shutdown mode is not poisoned"))
+                .finish()
+        }
+    }
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> ShutdownHandle {
+        let (tx, rx) = oneshot::channel();
+        ShutdownHandle {
+            inner: Arc::new(Inner {
+                mode: Mutex::new(Mode::Running),
+                task: AtomicWaker::new(),
+                closed: Mutex::new(Some(tx)),
+            }),
+            closed: rx.shared(),
+        }
+    }
+    /// Shut down the pool gracefully
+    ///
+    /// Pool stops accepting new requests, sends requests which are
+    /// already in the queue, waits until connections flush them and closes
+    /// connections. If this isn't done within `timeout` pool is closed
+    /// immediately.
+    pub fn graceful(&self, timeout: Duration) -> ShutdownFuture {
+        self.request(Mode::Graceful(Instant::now() + timeout))
+    }
+    /// Shut down the pool immediately
+    ///
+    /// Requests in the queue are dropped and connections are closed
+    /// without flushing.
+    pub fn immediate(&self) -> ShutdownFuture {
+        self.request(Mode::Immediate)
+    }
+    fn request(&self, new: Mode) -> ShutdownFuture {
+        {
+            let mut mode = self.inner.mode.lock()
+                .expect("shutdown mode is not poisoned");
+            *mode = match (*mode, new) {
+                (Mode::Immediate, _) | (_, Mode::Immediate) => {
+                    Mode::Immediate
+                }
+                (Mode::Graceful(a), Mode::Graceful(b)) => {
+                    Mode::Graceful(a.min(b))
+                }
+                (_, new) => new,
+            };
+        }
+        self.inner.task.wake();
+        ShutdownFuture(self.closed.clone())
+    }
+}
+
+impl Future for ShutdownFuture {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // sender is dropped if pool future is dropped without
+        // completing, which means pool is closed too
+        self.0.poll_unpin(cx).map(|_| ())
+    }
+}
+
+impl fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShutdownHandle")
+            .field("mode", &*self.inner.mode.lock()
+                .expect("shutdown mode is not poisoned"))
+            .finish()
+    }
+}
+
+impl fmt::Debug for ShutdownFuture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ShutdownFuture")
+    }
+}
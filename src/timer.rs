@@ -0,0 +1,32 @@
+//! Timer abstraction used by connection pool
+//!
+//! Connection pool needs a timer for blacklisting failing addresses. By
+//! default `tokio::runtime::Handle` is used, but any other timer might
+//! be passed to ``PoolConfig::build`` to run pool on a custom executor.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::runtime::Handle;
+
+
+/// A future returned by ``Timer::sleep_until``
+pub type Sleep = Pin<Box<dyn Future<Output=()> + Send>>;
+
+/// A timer used by connection pool
+pub trait Timer: Send + Sync {
+    /// Returns a future which resolves at the specified time
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+/// A timer shared between parts of the pool
+pub(crate) type SharedTimer = Arc<dyn Timer>;
+
+impl Timer for Handle {
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        // sleep is registered in the timer of the runtime it's created in
+        let _guard = self.enter();
+        Box::pin(::tokio::time::sleep_until(deadline.into()))
+    }
+}
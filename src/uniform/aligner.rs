@@ -1,32 +1,37 @@
 use std::u32;
 use std::collections::{HashSet, HashMap, BTreeMap};
 use std::collections::btree_map::Entry::{Occupied};
-use std::net::SocketAddr;
+use std::collections::hash_map::Entry;
 
 use rand::{thread_rng, seq::sample_iter};
 
+use address::PoolAddress;
 
-pub(crate) struct Aligner {
-    items: BTreeMap<u32, HashSet<SocketAddr>>,
-    addrs: HashMap<SocketAddr, u32>,
+
+pub(crate) struct Aligner<P> {
+    items: BTreeMap<u32, HashSet<P>>,
+    addrs: HashMap<P, u32>,
+    /// Connections of removed addresses which are not closed yet
+    retired: HashMap<P, u32>,
 }
 
 
-impl Aligner {
-    pub fn new() -> Aligner {
+impl<P: PoolAddress> Aligner<P> {
+    pub fn new() -> Aligner<P> {
         Aligner {
             items: BTreeMap::new(),
             addrs: HashMap::new(),
+            retired: HashMap::new(),
         }
     }
     pub fn update<N, O>(&mut self, new: N, old: O)
-        where N: IntoIterator<Item=SocketAddr>,
-              O: IntoIterator<Item=SocketAddr>,
+        where N: IntoIterator<Item=P>,
+              O: IntoIterator<Item=P>,
     {
         {
             let zero = self.items.entry(0).or_insert_with(HashSet::new);
             for addr in new {
-                let n = *self.addrs.entry(addr).or_insert(0);
+                let n = *self.addrs.entry(addr.clone()).or_insert(0);
                 if n == 0 {
                     zero.insert(addr);
                 }
@@ -34,6 +39,9 @@ impl Aligner {
         }
         for addr in old {
             if let Some(n) = self.addrs.remove(&addr) {
+                if n > 0 {
+                    *self.retired.entry(addr.clone()).or_insert(0) += n;
+                }
                 match self.items.entry(n) {
                     Occupied(mut o) => {
                         o.get_mut().remove(&addr);
@@ -55,8 +63,8 @@ impl Aligner {
             _ => {}
         }
     }
-    pub fn get<F>(&mut self, limit: u32, blist: F) -> Option<SocketAddr>
-        where F: Fn(SocketAddr) -> bool
+    pub fn get<F>(&mut self, limit: u32, blist: F) -> Option<P>
+        where F: Fn(&P) -> bool
     {
         assert!(limit < u32::MAX);
         let mut result = None;
@@ -65,7 +73,7 @@ impl Aligner {
                 return None;
             }
             let mut candidate = sample_iter(&mut thread_rng(),
-                addrs.iter().filter(|&&x| !blist(x)).map(|&x| x),
+                addrs.iter().filter(|x| !blist(x)).cloned(),
                 1).unwrap_or_else(|v| v);
             match candidate.pop() {
                 Some(a) => {
@@ -79,16 +87,28 @@ impl Aligner {
         if let Some((num, addr)) = result {
             self.items.entry(num+1)
                 .or_insert_with(HashSet::new)
-                .insert(addr);
-            let old = self.addrs.insert(addr, num+1);
+                .insert(addr.clone());
+            let old = self.addrs.insert(addr.clone(), num+1);
             debug_assert_eq!(old, Some(num));
             return Some(addr);
         }
         return None;
     }
-    pub fn put(&mut self, addr: SocketAddr) {
+    pub fn put(&mut self, addr: P) {
+        // address was removed (and maybe added back) while connection
+        // was still active
+        if let Entry::Occupied(mut o) = self.retired.entry(addr.clone()) {
+            *o.get_mut() -= 1;
+            if *o.get() == 0 {
+                o.remove_entry();
+            }
+            return;
+        }
         if let Some(num) = self.addrs.get_mut(&addr) {
-            assert!(*num > 0);
+            debug_assert!(*num > 0, "slot of {} is freed twice", addr);
+            if *num == 0 {
+                return;
+            }
             match self.items.entry(*num) {
                 Occupied(mut o) => {
                     o.get_mut().remove(&addr);
@@ -145,7 +165,7 @@ mod test {
             (addr(3), 2),
         ].into_iter().collect::<HashMap<_, _>>());
 
-        let blist1 = &|x| x == addr(1);
+        let blist1 = &|x: &SocketAddr| *x == addr(1);
         for _ in 0..6 {
             *counter.entry(a.get(100, blist1).unwrap()).or_insert(0) += 1;
         }
@@ -191,4 +211,20 @@ mod test {
             (addr(4), 4),
         ].into_iter().collect::<HashMap<_, _>>());
     }
+
+    #[test]
+    fn readd() {
+        let mut a = Aligner::new();
+        a.update(vec![addr(1)], vec![]);
+        assert_eq!(a.get(2, |_| false), Some(addr(1)));
+        a.update(vec![], vec![addr(1)]);
+        a.update(vec![addr(1)], vec![]);
+        assert_eq!(a.get(2, |_| false), Some(addr(1)));
+        assert_eq!(a.get(2, |_| false), Some(addr(1)));
+        // connection of removed address doesn't free a new slot
+        a.put(addr(1));
+        assert_eq!(a.get(2, |_| false), None);
+        a.put(addr(1));
+        assert_eq!(a.get(2, |_| false), Some(addr(1)));
+    }
 }
@@ -1,80 +1,110 @@
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::net::SocketAddr;
+use std::collections::VecDeque;
 use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+#[cfg(feature="tracing")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
 
-use futures::Async;
-use futures::task::{self, Task};
+use address::PoolAddress;
+use concurrency::Slot;
 use uniform::Connections;
 
 
 pub enum Action<I> {
-    StartSend(I),
+    StartSend(Request<I>),
     Poll,
     Close,
 }
 
-pub(in uniform) struct Inner<I> {
-    addr: SocketAddr,
-    request: Option<I>,
-    connections: Rc<RefCell<Connections<I>>>,
-    task: Option<Task>,
-    pub(in uniform) queued: bool,
+/// A request dispatched to a connection
+pub(in uniform) struct Request<I> {
+    pub item: I,
+    /// A copy of the item to retry if connection fails (see `retry`)
+    pub copy: Option<I>,
+    /// Number of times request was already sent
+    pub attempt: u32,
+    /// Concurrency limit slot taken by the request (see `concurrency`)
+    pub slot: Option<Slot>,
+}
+
+pub(in uniform) struct Inner<I, P> {
+    addr: P,
+    request: Option<Request<I>>,
+    connections: Arc<Mutex<Connections<I, P>>>,
+    task: Option<Waker>,
+    queued: bool,
     // TODO(tailhook) verify that close flag is okay
-    pub(in uniform) closed: bool,
+    closed: bool,
+    #[cfg(feature="tracing")]
+    span: ::tracing::Span,
+}
+
+#[cfg(feature="tracing")]
+static CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Controller<I, P> {
+    inner: Arc<Mutex<Inner<I, P>>>,
 }
 
-pub struct Controller<I> {
-    pub(in uniform) inner: Rc<RefCell<Inner<I>>>,
+pub(in uniform) struct Helper<I, P> {
+    inner: Arc<Mutex<Inner<I, P>>>,
 }
 
-pub(in uniform) struct Helper<I> {
-    pub(in uniform) inner: Rc<RefCell<Inner<I>>>,
+/// Connections are accessed by the pool task only, so lock is never
+/// contended, it's only needed for the pool to be `Send`
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("connection state is not poisoned")
 }
 
-impl<I> PartialEq for Controller<I> {
-    fn eq(&self, other: &Controller<I>) -> bool {
-        &*self.inner.borrow() as *const _ == &*other.inner.borrow() as *const _
+impl<I, P> PartialEq for Controller<I, P> {
+    fn eq(&self, other: &Controller<I, P>) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
     }
 }
 
-impl<I> Eq for Controller<I> {}
+impl<I, P> Eq for Controller<I, P> {}
 
-impl<I> Hash for Controller<I> {
+impl<I, P> Hash for Controller<I, P> {
     fn hash<H: Hasher>(&self, hasher: &mut H) {
-        hasher.write_usize((&*self.inner.borrow() as *const _) as usize);
+        hasher.write_usize(Arc::as_ptr(&self.inner) as *const u8 as usize);
     }
 }
 
-impl<I> Helper<I> {
-    pub fn new(addr: SocketAddr, connections: Rc<RefCell<Connections<I>>>)
-        -> Helper<I>
+impl<I, P: PoolAddress> Helper<I, P> {
+    pub fn new(addr: P, connections: Arc<Mutex<Connections<I, P>>>)
+        -> Helper<I, P>
     {
-        let inner = Rc::new(RefCell::new(Inner {
+        #[cfg(feature="tracing")]
+        let span = ::tracing::debug_span!("connection",
+            addr=%addr,
+            id=CONNECTION_ID.fetch_add(1, Ordering::Relaxed));
+        let inner = Arc::new(Mutex::new(Inner {
             addr, connections,
             task: None,
             queued: false,
             closed: false,
             request: None,
+            #[cfg(feature="tracing")]
+            span,
         }));
         return Helper { inner }
     }
-    pub fn controller(&self) -> Controller<I> {
+    pub fn controller(&self) -> Controller<I, P> {
         Controller {
             inner: self.inner.clone(),
         }
     }
-    pub fn poll_close(&self) -> Async<()> {
-        let mut cell = self.inner.borrow_mut();
-        cell.task = Some(task::current());
+    pub fn poll_close(&self, cx: &mut Context) -> Poll<()> {
+        let mut cell = lock(&self.inner);
+        cell.task = Some(cx.waker().clone());
         if cell.closed {
-            Async::Ready(())
+            Poll::Ready(())
         } else {
-            Async::NotReady
+            Poll::Pending
         }
     }
     pub fn take(&self) -> Action<I> {
-        let mut cell = self.inner.borrow_mut();
+        let mut cell = lock(&self.inner);
         if cell.closed {
             return Action::Close;
         }
@@ -83,58 +113,106 @@ impl<I> Helper<I> {
             None => Action::Poll,
         }
     }
-    pub fn backpressure(&self, value: I) {
-        let mut cell = self.inner.borrow_mut();
+    pub fn backpressure(&self, value: Request<I>) {
+        let mut cell = lock(&self.inner);
         cell.request = Some(value);
         assert!(!cell.queued);
     }
-    pub fn requeue(&self) {
+    pub fn requeue(&self, cx: &mut Context) {
         let connections = {
-            let mut cell = self.inner.borrow_mut();
-            cell.task = Some(task::current());
+            let mut cell = lock(&self.inner);
+            cell.task = Some(cx.waker().clone());
             if cell.queued {
                 return;
             }
             cell.connections.clone()
         };
-        connections.borrow_mut().add(self.controller());
+        lock(&connections).add(self.controller());
     }
     pub fn closed(&self) {
-        self.inner.borrow_mut().closed = true;
+        lock(&self.inner).closed = true;
+    }
+    /// Pass requests which might not have been delivered back to the pool
+    pub fn lost(&self, requests: &mut VecDeque<Request<I>>) {
+        if requests.is_empty() {
+            return;
+        }
+        let con = lock(&self.inner).connections.clone();
+        lock(&con).lost.extend(requests.drain(..));
+    }
+    /// Maximum number of request copies kept by the connection
+    pub fn max_unflushed(&self) -> usize {
+        let con = lock(&self.inner).connections.clone();
+        let max = lock(&con).max_unflushed;
+        max
+    }
+    pub fn addr(&self) -> P {
+        lock(&self.inner).addr.clone()
     }
-    pub fn addr(&self) -> SocketAddr {
-        self.inner.borrow().addr
+    #[cfg(feature="tracing")]
+    pub fn span(&self) -> ::tracing::Span {
+        lock(&self.inner).span.clone()
     }
 }
 
-impl<I> Controller<I> {
+impl<I, P: PoolAddress> Controller<I, P> {
     pub fn close(&self) {
-        let mut inner = self.inner.borrow_mut();
+        let mut inner = lock(&self.inner);
         inner.closed = true;
-        inner.task.as_ref().map(|x| x.notify());
+        inner.task.as_ref().map(|x| x.wake_by_ref());
     }
     pub fn is_closed(&self) -> bool {
-        self.inner.borrow().closed
+        lock(&self.inner).closed
     }
-    pub fn request_back(&self) -> Option<I> {
-        let mut cell = self.inner.borrow_mut();
+    pub fn request_back(&self) -> Option<Request<I>> {
+        let mut cell = lock(&self.inner);
         let res = cell.request.take();
         res
     }
-    pub fn request(&self, item: I) {
-        let mut inner = self.inner.borrow_mut();
+    pub fn request(&self, item: Request<I>) {
+        let mut inner = lock(&self.inner);
         assert!(inner.request.is_none());
         inner.request = Some(item);
-        inner.task.as_ref().map(|x| x.notify());
+        inner.task.as_ref().map(|x| x.wake_by_ref());
     }
-    pub fn addr(&self) -> SocketAddr {
-        self.inner.borrow().addr
+    pub fn addr(&self) -> P {
+        lock(&self.inner).addr.clone()
+    }
+    #[cfg(feature="tracing")]
+    pub fn span(&self) -> ::tracing::Span {
+        lock(&self.inner).span.clone()
+    }
+}
+
+impl<I, P> Controller<I, P> {
+    /// Marks controller as queued, returns false if it's already queued
+    /// or closed
+    pub(in uniform) fn set_queued(&self) -> bool {
+        let mut inner = lock(&self.inner);
+        if inner.queued || inner.closed {
+            return false;
+        }
+        inner.queued = true;
+        true
+    }
+    pub(in uniform) fn unset_queued(&self) {
+        let mut inner = lock(&self.inner);
+        assert!(inner.queued);
+        inner.queued = false;
     }
 }
 
-impl<I> Drop for Helper<I> {
+impl<I, P> Drop for Helper<I, P> {
     fn drop(&mut self) {
-        let con = self.inner.borrow().connections.clone();
-        con.borrow_mut().all.remove(&self.controller());
+        let (con, request) = {
+            let mut inner = lock(&self.inner);
+            (inner.connections.clone(), inner.request.take())
+        };
+        let mut con = lock(&con);
+        con.all.remove(&Controller { inner: self.inner.clone() });
+        // request was never sent, so it's safe to dispatch it again
+        if let Some(request) = request {
+            con.requeue.push_back(request);
+        }
     }
 }
@@ -1,49 +1,67 @@
-use futures::{Future, Async, Sink};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
+use futures::Sink;
+
+use address::PoolAddress;
 use uniform::{FutureOk, FutureErr};
 use uniform::chan::Helper;
 
 
-pub(in uniform) struct ConnectFuture<F>
-    where F: Future,
-          F::Item: Sink,
-{
-    task: Option<Helper<<F::Item as Sink>::SinkItem>>,
-    future: F,
+pub(in uniform) struct ConnectFuture<F, I, P> {
+    task: Option<Helper<I, P>>,
+    future: Pin<Box<F>>,
+    phantom: PhantomData<fn(I)>,
+    #[cfg(feature="tracing")]
+    span: ::tracing::Span,
 }
 
-impl<F: Future> ConnectFuture<F>
-    where F: Future,
-          F::Item: Sink,
-{
-    pub fn new(task: Helper<<F::Item as Sink>::SinkItem>, future: F)
-        -> ConnectFuture<F>
+// future is pinned in a box, other fields are never pinned
+impl<F, I, P> Unpin for ConnectFuture<F, I, P> {}
+
+impl<F: Future, I, P: PoolAddress> ConnectFuture<F, I, P> {
+    pub fn new(task: Helper<I, P>, future: F)
+        -> ConnectFuture<F, I, P>
     {
-        ConnectFuture { task: Some(task), future }
+        #[cfg(feature="tracing")]
+        let span = ::tracing::debug_span!(parent: &task.span(), "connect",
+            addr=%task.addr());
+        ConnectFuture {
+            task: Some(task),
+            future: Box::pin(future),
+            phantom: PhantomData,
+            #[cfg(feature="tracing")]
+            span,
+        }
     }
 }
 
-impl<F: Future> Future for ConnectFuture<F>
-    where F::Item: Sink,
+impl<F, S, E, I, P: PoolAddress> Future for ConnectFuture<F, I, P>
+    where F: Future<Output=Result<S, E>>,
+          S: Sink<I>,
 {
-    type Item = FutureOk<F::Item>;
-    type Error = FutureErr<F::Error, <F::Item as Sink>::SinkError>;
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+    type Output = Result<FutureOk<S, I, P>, FutureErr<E, S::Error, P>>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        #[cfg(feature="tracing")]
+        let _enter = this.span.enter();
         let snk = {
-            let task = self.task.as_ref().expect("poll invariant");
-            match task.poll_close() {
-                Async::Ready(()) => {
-                    return Ok(Async::Ready(FutureOk::Aborted(task.addr())));
-                }
-                Async::NotReady => {}
+            let task = this.task.as_ref().expect("poll invariant");
+            if task.poll_close(cx).is_ready() {
+                return Poll::Ready(Ok(FutureOk::Aborted(task.addr())));
             }
-            match self.future.poll() {
-                Ok(Async::Ready(s)) => s,
-                Ok(Async::NotReady) => return Ok(Async::NotReady),
-                Err(e) => return Err(FutureErr::CantConnect(task.addr(), e)),
+            match this.future.as_mut().poll(cx) {
+                Poll::Ready(Ok(s)) => s,
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => {
+                    return Poll::Ready(Err(
+                        FutureErr::CantConnect(task.addr(), e)));
+                }
             }
         };
-        let task = self.task.take().expect("poll invariant");
-        Ok(Async::Ready(FutureOk::Connected(task, snk)))
+        let task = this.task.take().expect("poll invariant");
+        Poll::Ready(Ok(FutureOk::Connected(task, snk)))
     }
 }
@@ -1,93 +1,161 @@
 use std::collections::{HashSet, BinaryHeap};
-use std::cmp::Ordering;
-use std::net::SocketAddr;
+use std::cmp::{Ordering, Reverse};
+use std::task::{Context, Poll};
 use std::time::Instant;
 
-use futures::{Future, Async};
-use tokio_core::reactor::{Handle, Timeout};
+use futures::FutureExt;
 
+use address::PoolAddress;
+use timer::{Sleep, SharedTimer};
 
-pub(crate) struct Blacklist {
-    addrs: HashSet<SocketAddr>,
-    heap: BinaryHeap<Pair>,
-    timeout: Option<Timeout>,
-    handle: Handle,
+
+pub(crate) struct Blacklist<P> {
+    addrs: HashSet<P>,
+    heap: BinaryHeap<Pair<P>>,
+    timeout: Option<Sleep>,
+    timer: SharedTimer,
+}
+
+/// Connection slots which wait before connecting again
+///
+/// Unlike `Blacklist` the address itself is not excluded, so other
+/// connections to it are established as usual. Used after initialization
+/// errors.
+pub(crate) struct Backoff<P> {
+    heap: BinaryHeap<Reverse<Pair<P>>>,
+    timeout: Option<(Instant, Sleep)>,
+    timer: SharedTimer,
 }
 
-#[derive(Eq)]
-pub struct Pair(Instant, SocketAddr);
+pub struct Pair<P>(Instant, P);
 
-impl PartialOrd for Pair {
-    fn partial_cmp(&self, other: &Pair) -> Option<Ordering> {
+impl<P> PartialOrd for Pair<P> {
+    fn partial_cmp(&self, other: &Pair<P>) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for Pair {
-    fn cmp(&self, other: &Pair) -> Ordering {
+impl<P> Ord for Pair<P> {
+    fn cmp(&self, other: &Pair<P>) -> Ordering {
         self.0.cmp(&other.0)
     }
 }
 
-impl PartialEq for Pair {
-    fn eq(&self, other: &Pair) -> bool {
+impl<P> PartialEq for Pair<P> {
+    fn eq(&self, other: &Pair<P>) -> bool {
         self.0.eq(&other.0)
     }
 }
 
-impl Blacklist {
-    pub fn new(h: &Handle) -> Blacklist {
+impl<P> Eq for Pair<P> {}
+
+impl<P: PoolAddress> Blacklist<P> {
+    pub fn new(timer: &SharedTimer) -> Blacklist<P> {
         Blacklist {
             addrs: HashSet::new(),
             heap: BinaryHeap::new(),
             timeout: None,
-            handle: h.clone(),
+            timer: timer.clone(),
         }
     }
-    pub fn blacklist(&mut self, addr: SocketAddr, time: Instant) {
+    pub fn blacklist(&mut self, addr: P, time: Instant) {
         if self.addrs.contains(&addr) {
             // can't add again because is in heap
             return;
         }
-        self.heap.push(Pair(time, addr));
+        self.heap.push(Pair(time, addr.clone()));
         self.addrs.insert(addr);
     }
-    pub fn is_failing(&self, addr: SocketAddr) -> bool {
-        return self.addrs.contains(&addr);
+    pub fn is_failing(&self, addr: &P) -> bool {
+        return self.addrs.contains(addr);
     }
-    pub fn poll(&mut self) -> Async<SocketAddr> {
+    pub fn poll(&mut self, cx: &mut Context) -> Poll<P> {
         loop {
             match self.heap.peek() {
-                Some(&Pair(time, a)) if time <= Instant::now() => {
+                Some(&Pair(time, _)) if time <= Instant::now() => {
                     self.timeout = None;
+                    let Pair(_, a) = self.heap.pop().expect("peeked");
                     self.addrs.remove(&a);
-                    self.heap.pop();
-                    return Async::Ready(a);
+                    return Poll::Ready(a);
                 }
                 Some(&Pair(time, _)) => {
                     let timer_result = self.timeout.as_mut()
-                        .map(|x| x.poll().expect("timeout never fails"));
+                        .map(|x| x.poll_unpin(cx));
                     match timer_result {
-                        Some(Async::NotReady) => return Async::NotReady,
+                        Some(Poll::Pending) => return Poll::Pending,
                         _ => {
                             self.timeout = None;
                         }
                     }
-                    let mut timer = Timeout::new_at(time, &self.handle)
-                        .expect("timeout never fails");
-                    match timer.poll().expect("timeout never fails") {
-                        Async::Ready(()) => continue,
-                        Async::NotReady => {
+                    let mut timer = self.timer.sleep_until(time);
+                    match timer.poll_unpin(cx) {
+                        Poll::Ready(()) => continue,
+                        Poll::Pending => {
                             self.timeout = Some(timer);
-                            return Async::NotReady;
+                            return Poll::Pending;
                         }
                     }
                 }
                 None => {
                     self.timeout = None;
-                    return Async::NotReady;
+                    return Poll::Pending;
                 }
             }
         }
     }
 }
+
+impl<P: PoolAddress> Backoff<P> {
+    pub fn new(timer: &SharedTimer) -> Backoff<P> {
+        Backoff {
+            heap: BinaryHeap::new(),
+            timeout: None,
+            timer: timer.clone(),
+        }
+    }
+    pub fn delay(&mut self, addr: P, time: Instant) {
+        self.heap.push(Reverse(Pair(time, addr)));
+    }
+    pub fn is_waiting(&self, addr: &P) -> bool {
+        self.heap.iter().any(|&Reverse(Pair(_, ref a))| a == addr)
+    }
+    /// Removes slots of the addresses, returns an address for every
+    /// removed slot
+    pub fn remove(&mut self, addrs: &[P]) -> Vec<P> {
+        let (removed, kept): (Vec<_>, Vec<_>) = self.heap.drain()
+            .partition(|&Reverse(Pair(_, ref a))| addrs.contains(a));
+        self.heap = kept.into_iter().collect();
+        // deadline might be changed, timer is set again on the next poll
+        self.timeout = None;
+        removed.into_iter().map(|Reverse(Pair(_, a))| a).collect()
+    }
+    /// Returns address of a slot which may be connected again
+    pub fn poll(&mut self, cx: &mut Context) -> Poll<P> {
+        let time = match self.heap.peek() {
+            Some(&Reverse(Pair(time, _))) => time,
+            None => {
+                self.timeout = None;
+                return Poll::Pending;
+            }
+        };
+        let expired = time <= Instant::now() || match self.timeout {
+            // new slot might be added with an earlier deadline
+            Some((deadline, ref mut timeout)) if deadline == time => {
+                timeout.poll_unpin(cx).is_ready()
+            }
+            _ => {
+                let mut timeout = self.timer.sleep_until(time);
+                let res = timeout.poll_unpin(cx);
+                self.timeout = Some((time, timeout));
+                res.is_ready()
+            }
+        };
+        if expired {
+            self.timeout = None;
+            let Reverse(Pair(_, a)) = self.heap.pop().expect("peeked");
+            Poll::Ready(a)
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -12,163 +12,366 @@ mod failures;
 mod sink;
 mod pool;
 
-use std::cell::RefCell;
-use std::collections::{VecDeque, HashSet};
-use std::net::SocketAddr;
-use std::rc::Rc;
+use std::collections::{VecDeque, HashSet, HashMap};
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
-use abstract_ns::Address;
-use futures::{Future, Async, Sink, AsyncSink, Stream};
+use futures::{FutureExt, Sink, Stream, StreamExt};
 use futures::stream::FuturesUnordered;
+use futures::task::AtomicWaker;
 use rand::{thread_rng, Rng};
-use tokio_core::reactor::Handle;
-use void::{Void, unreachable};
 
+use address::{AddressSet, PoolAddress};
 use config::{NewMux, private};
+use config::private::{AsyncSink, Mux};
+use config::private::{RetryPolicy, RoutePolicy, FailPolicy, LimitPolicy};
 use error_log::{ErrorLog, ShutdownReason};
+use events::{Event, DisconnectReason, Subscribers};
 use connect::Connect;
+use concurrency::{Host, NoLimit};
+use fail_fast::Wait;
 use metrics::Collect;
+use retry::{NoRetry, Budget};
+use route::NoRouting;
+use timer::{SharedTimer, Sleep};
 use uniform::aligner::Aligner;
-use uniform::chan::{Controller, Helper};
+use uniform::chan::{Controller, Helper, Request};
 use uniform::connect::ConnectFuture;
-use uniform::failures::Blacklist;
+use uniform::failures::{Blacklist, Backoff};
 use uniform::sink::SinkFuture;
 use uniform::pool::Lazy;
 
 
-enum FutureOk<S>
-    where S: Sink
-{
-    Connected(Helper<S::SinkItem>, S),
+enum FutureOk<S, I, P> {
+    Connected(Helper<I, P>, S),
     /// Aborted connect attempt (i.e. when establishing or handshaking)
-    Aborted(SocketAddr),
+    Aborted(P),
     /// Closed working connection
-    Closed(SocketAddr),
+    Closed(P),
+}
+
+enum FutureErr<E, F, P> {
+    CantConnect(P, E),
+    Disconnected(P, F),
 }
 
-enum FutureErr<E, F> {
-    CantConnect(SocketAddr, E),
-    Disconnected(SocketAddr, F),
+/// A request with routing hints waiting for a connection to its hosts
+struct Parked<I, P> {
+    req: Request<I>,
+    /// Hosts the request must not be sent to
+    excluded: HashSet<P>,
+    /// Request is failed if it's still waiting at this time
+    deadline: Instant,
+}
+
+/// Whether there are backends requests might be sent to
+enum Health {
+    Healthy,
+    /// No healthy backends, requests are failed when sleep finishes
+    Unhealthy(Sleep),
+    /// No healthy backends for a timeout, requests are failed
+    Failing,
 }
 
 /// A constructor for a uniform connection pool with lazy connections
-pub struct LazyUniform {
+///
+/// Type parameters are retry policy (see ``PoolConfig::retry``), routing
+/// policy (see ``PoolConfig::routing``), policy of failing requests
+/// (see ``PoolConfig::fail_fast``) and concurrency limit (see
+/// ``PoolConfig::adaptive_concurrency``).
+pub struct LazyUniform<R=NoRetry, H=NoRouting, F=Wait, L=NoLimit> {
+    pub(crate) config: Config,
+    pub(crate) updates: Option<Pin<Box<dyn Stream<Item=Config> + Send>>>,
+    pub(crate) retry: R,
+    pub(crate) route: H,
+    pub(crate) fail: F,
+    pub(crate) limit: L,
+}
+
+/// Runtime configuration of the uniform connection pool
+///
+/// A stream of these values might be passed to
+/// ``PoolConfig::config_stream`` to reconfigure pool without restarting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
     pub(crate) conn_limit: u32,
-    pub(crate) reconnect_timeout: Duration,
+    pub(crate) reconnect_min: Duration,
+    pub(crate) reconnect_max: Duration,
+    pub(crate) fail_timeout: Option<Duration>,
+    pub(crate) park_timeout: Duration,
+}
+
+/// Config stream (see ``PoolConfig::config_stream``) isn't cloned, as
+/// every pool needs its own one
+impl<R, H, F, L> Clone for LazyUniform<R, H, F, L>
+    where R: Clone, H: Clone, F: Clone, L: Clone,
+{
+    fn clone(&self) -> Self {
+        LazyUniform {
+            config: self.config.clone(),
+            updates: None,
+            retry: self.retry.clone(),
+            route: self.route.clone(),
+            fail: self.fail.clone(),
+            limit: self.limit.clone(),
+        }
+    }
+}
+
+impl Config {
+    /// Create a configuration with specified number of per-host connections
+    ///
+    /// Reconnect timeout is 100 ms by default, requests with routing
+    /// hints wait for their hosts for at most 10 seconds.
+    pub fn new(conn_limit: u32) -> Config {
+        assert!(conn_limit < u32::MAX);
+        Config {
+            conn_limit,
+            reconnect_min: Duration::from_millis(50),
+            reconnect_max: Duration::from_millis(150),
+            fail_timeout: None,
+            park_timeout: Duration::from_secs(10),
+        }
+    }
+    /// Set an average time an address is blacklisted after connection error
+    ///
+    /// Real time is randomized between 0.5 and 1.5 of this value.
+    pub fn reconnect_timeout(mut self, timeout: Duration) -> Config {
+        self.reconnect_min = timeout / 2;
+        self.reconnect_max = timeout * 3 / 2;
+        self
+    }
+    /// Set bounds of time an address is blacklisted after connection error
+    ///
+    /// Real time is chosen randomly between the two values.
+    pub fn reconnect_backoff(mut self, min: Duration, max: Duration)
+        -> Config
+    {
+        assert!(min <= max);
+        self.reconnect_min = min;
+        self.reconnect_max = max;
+        self
+    }
+    /// Override timeout of the ``fail_fast::FailFast`` policy
+    ///
+    /// Has no effect unless pool is configured with
+    /// ``PoolConfig::fail_fast``.
+    pub fn fail_fast_timeout(mut self, timeout: Duration) -> Config {
+        self.fail_timeout = Some(timeout);
+        self
+    }
+    /// Set maximum time a request with a routing hint waits for a
+    /// connection to its hosts
+    ///
+    /// When it passes request is treated like one lost on a broken
+    /// connection, i.e. it's dispatched again if ``PoolConfig::retry``
+    /// allows, otherwise it's dropped.
+    pub fn park_timeout(mut self, timeout: Duration) -> Config {
+        self.park_timeout = timeout;
+        self
+    }
+    fn reconnect_ms(&self) -> (u64, u64) {
+        (to_ms(self.reconnect_min), to_ms(self.reconnect_max))
+    }
+}
+
+fn to_ms(dur: Duration) -> u64 {
+    dur.as_secs() * 1000 + (dur.subsec_nanos() / 1000_000) as u64
+}
+
+/// Maximum number of requests with routing hints waiting for their hosts,
+/// when exceeded such requests block the pool like any other ones
+const MAX_PARKED: usize = 1000;
+
+type AddrOf<C> = <C as Connect>::Address;
+
+/// Connections are accessed by the pool task only, so lock is never
+/// contended, it's only needed for the pool to be `Send`
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().expect("connections are not poisoned")
 }
 
-struct Connections<I> {
-    queue: VecDeque<Controller<I>>,
-    all: HashSet<Controller<I>>,
+struct Connections<I, P> {
+    queue: VecDeque<Controller<I, P>>,
+    all: HashSet<Controller<I, P>>,
+    /// Requests returned by closed connections before sending
+    requeue: VecDeque<Request<I>>,
+    /// Copies of requests which might be lost on broken connections
+    lost: VecDeque<Request<I>>,
+    /// Maximum number of copies kept by a single connection
+    max_unflushed: usize,
+    /// Some connection got ready since parked requests were dispatched
+    ready_changed: bool,
 }
 
-impl<I> Connections<I> {
-    fn new() -> Connections<I>{
+impl<I, P> Connections<I, P> {
+    fn new(max_unflushed: usize) -> Connections<I, P>{
         Connections {
             queue: VecDeque::new(),
             all: HashSet::new(),
+            requeue: VecDeque::new(),
+            lost: VecDeque::new(),
+            max_unflushed,
+            ready_changed: false,
         }
     }
-    fn add(&mut self, ctr: Controller<I>) {
-        {
-            let mut inner = ctr.inner.borrow_mut();
-            assert!(!inner.closed);
-            assert!(!inner.queued);
-            inner.queued = true;
-        }
+    fn add(&mut self, ctr: Controller<I, P>) {
+        assert!(ctr.set_queued(), "controller is already queued or closed");
         self.queue.push_back(ctr);
+        self.ready_changed = true;
     }
     fn has_ready(&self) -> bool {
         self.queue.len() > 0
     }
-    fn next(&mut self) -> Option<Controller<I>> {
+    /// Returns controllers skipped by routing back to the front of queue
+    fn put_back(&mut self, skipped: Vec<Controller<I, P>>) {
+        for ctr in skipped.into_iter().rev() {
+            // might be requeued by itself in the meantime
+            if !ctr.set_queued() {
+                continue;
+            }
+            self.queue.push_front(ctr);
+        }
+    }
+    fn next(&mut self) -> Option<Controller<I, P>> {
         self.queue.pop_front()
         .map(|ctr| {
-            {
-                let mut inner = ctr.inner.borrow_mut();
-                assert!(inner.queued);
-                inner.queued = false;
-            }
+            ctr.unset_queued();
             ctr
         })
     }
 }
-impl<A, C, E, M> NewMux<A, C, E, M> for LazyUniform
-    where A: Stream<Item=Address, Error=Void>,
-          C: Connect + 'static,
-          <<C as Connect>::Future as Future>::Item: Sink,
-          E: ErrorLog<
-            ConnectionError=<C::Future as Future>::Error,
-            SinkError=<<C::Future as Future>::Item as Sink>::SinkError,
+impl<I, A, C, E, M, R, H, F, L> NewMux<I, A, C, E, M>
+    for LazyUniform<R, H, F, L>
+    where A: Stream + Send + 'static,
+          A::Item: AddressSet<Addr=<C as Connect>::Address>,
+          C: Connect + Send + 'static,
+          C::Future: Send + 'static,
+          C::Connection: Sink<I> + Send + 'static,
+          C::Error: Send + 'static,
+          <C::Connection as Sink<I>>::Error: Send + 'static,
+          I: Send + 'static,
+          E: ErrorLog<<C as Connect>::Address,
+            ConnectionError=C::Error,
+            SinkError=<C::Connection as Sink<I>>::Error,
             >,
           E: 'static,
           M: Collect + 'static,
+          R: RetryPolicy<I>,
+          H: RoutePolicy<I, C::Address>,
+          F: FailPolicy<I>,
+          L: LimitPolicy<I>,
 {}
 
-impl<A, C, E, M> private::NewMux<A, C, E, M> for LazyUniform
-    where A: Stream<Item=Address, Error=Void>,
-          C: Connect + 'static,
-          <<C as Connect>::Future as Future>::Item: Sink,
-          E: ErrorLog<
-            ConnectionError=<C::Future as Future>::Error,
-            SinkError=<<C::Future as Future>::Item as Sink>::SinkError,
+impl<I, A, C, E, M, R, H, F, L> private::NewMux<I, A, C, E, M>
+    for LazyUniform<R, H, F, L>
+    where A: Stream + Send + 'static,
+          A::Item: AddressSet<Addr=<C as Connect>::Address>,
+          C: Connect + Send + 'static,
+          C::Future: Send + 'static,
+          C::Connection: Sink<I> + Send + 'static,
+          C::Error: Send + 'static,
+          <C::Connection as Sink<I>>::Error: Send + 'static,
+          I: Send + 'static,
+          E: ErrorLog<<C as Connect>::Address,
+            ConnectionError=C::Error,
+            SinkError=<C::Connection as Sink<I>>::Error,
             >,
           E: 'static,
           M: Collect + 'static,
+          R: RetryPolicy<I>,
+          H: RoutePolicy<I, C::Address>,
+          F: FailPolicy<I>,
+          L: LimitPolicy<I>,
 {
-    type Sink = Lazy<A, C, E, M>;
+    type Sink = Lazy<I, A, C, E, M, R, H, F, L>;
     fn construct(self,
-        h: &Handle, address: A, connector: C, errors: E, metrics: M)
-        -> Lazy<A, C, E, M>
+        timer: &SharedTimer, address: A, connector: C, errors: E, metrics: M,
+        events: Subscribers)
+        -> Lazy<I, A, C, E, M, R, H, F, L>
     {
-        let reconn_ms = self.reconnect_timeout.as_secs() * 1000 +
-            (self.reconnect_timeout.subsec_nanos() / 1000_000) as u64;
         Lazy {
-            conn_limit: self.conn_limit,
-            reconnect_ms: (reconn_ms / 2, reconn_ms * 3 / 2),
+            budget: match self.retry.settings() {
+                Some(s) => Budget::new(s.reserve, s.ratio),
+                None => Budget::new(0., 0.),
+            },
+            connections: Arc::new(Mutex::new(Connections::new(
+                self.retry.settings().map(|s| s.max_unflushed)
+                    .unwrap_or(0)))),
+            retry: self.retry,
+            route: self.route,
+            fail: self.fail,
+            limit: self.limit,
+            hosts: HashMap::new(),
+            limit_task: Arc::new(AtomicWaker::new()),
+            health: Health::Healthy,
+            timer: timer.clone(),
+            conn_limit: self.config.conn_limit,
+            reconnect_ms: self.config.reconnect_ms(),
+            fail_timeout: self.config.fail_timeout,
+            park_timeout: self.config.park_timeout,
+            updates: self.updates,
             futures: FuturesUnordered::new(),
-            connections: Rc::new(RefCell::new(Connections::new())),
-            blist: Blacklist::new(h),
+            blist: Blacklist::new(timer),
+            backoff: Backoff::new(timer),
             aligner: Aligner::new(),
             closing: false,
-            cur_address: [][..].into(),
-            address, connector, errors, metrics,
+            cur_address: HashSet::new(),
+            parked: VecDeque::new(),
+            park_sleep: None,
+            rescan_parked: false,
+            address: Box::pin(address),
+            connector, errors, metrics, events,
         }
     }
 }
 
-impl<A, C, E, M> Lazy<A, C, E, M>
-    where A: Stream<Item=Address, Error=Void>,
-          C: Connect + 'static,
-          <<C as Connect>::Future as Future>::Item: Sink,
-          E: ErrorLog<
-            ConnectionError=<C::Future as Future>::Error,
-            SinkError=<<C::Future as Future>::Item as Sink>::SinkError,
-          >,
+impl<I, A, C, E, M, R, H, F, L> Lazy<I, A, C, E, M, R, H, F, L>
+    where A: Stream + Send + 'static,
+          A::Item: AddressSet<Addr=<C as Connect>::Address>,
+          C: Connect + Send + 'static,
+          C::Future: Send + 'static,
+          C::Connection: Sink<I> + Send + 'static,
+          C::Error: Send + 'static,
+          <C::Connection as Sink<I>>::Error: Send + 'static,
+          I: Send + 'static,
+          E: ErrorLog<<C as Connect>::Address,
+            ConnectionError=C::Error,
+            SinkError=<C::Connection as Sink<I>>::Error,
+            >,
+          E: 'static,
           M: Collect + 'static,
+          R: RetryPolicy<I>,
+          H: RoutePolicy<I, C::Address>,
+          F: FailPolicy<I>,
+          L: LimitPolicy<I>,
 {
-    fn new_addr(&mut self) -> Option<Address> {
+    fn new_addr(&mut self, cx: &mut Context) -> Option<A::Item> {
         let mut result = None;
         loop {
-            match self.address.poll() {
-                Ok(Async::Ready(Some(addr))) => result = Some(addr),
-                Ok(Async::Ready(None)) => {
+            match self.address.poll_next_unpin(cx) {
+                Poll::Ready(Some(addr)) => result = Some(addr),
+                Poll::Ready(None) => {
                     self.errors.pool_shutting_down(
                         ShutdownReason::AddressStreamClosed);
+                    self.events.shutting_down(
+                        ShutdownReason::AddressStreamClosed);
                     self.start_closing();
                     result = None;
                     break;
                 }
-                Ok(Async::NotReady) => break,
-                Err(e) => unreachable(e),
+                Poll::Pending => break,
             }
         }
         return result;
     }
-    fn check_for_address_updates(&mut self) {
-        let new_addr = match self.new_addr() {
+    fn check_for_address_updates(&mut self, cx: &mut Context) {
+        let new_addr = match self.new_addr(cx) {
             Some(new) => {
+                let new = new.addresses();
                 if new != self.cur_address {
                     new
                 } else {
@@ -177,29 +380,115 @@ impl<A, C, E, M> Lazy<A, C, E, M>
             }
             _ => return,
         };
-        let (old, new) = self.cur_address.at(0)
-                       .compare_addresses(&new_addr.at(0));
+        let old = self.cur_address.difference(&new_addr)
+            .cloned().collect::<Vec<_>>();
+        let new = new_addr.difference(&self.cur_address)
+            .cloned().collect::<Vec<_>>();
         debug!("New address, to be retired {:?}, \
                 to be connected {:?}", old, new);
-        for task in &self.connections.borrow().all {
+        for task in &lock(&self.connections).all {
             if old.contains(&task.addr()) {
                 task.close();
             }
         }
+        if self.events.has_subscribers() {
+            self.events.emit(Event::AddressChanged {
+                added: new.iter().map(|a| a.endpoint()).collect(),
+                removed: old.iter().map(|a| a.endpoint()).collect(),
+            });
+        }
+        if let Some(settings) = self.limit.settings() {
+            for addr in &old {
+                self.hosts.remove(addr);
+            }
+            for addr in &new {
+                self.hosts.insert(addr.clone(),
+                    Arc::new(Host::new(settings, &self.limit_task)));
+            }
+        }
+        // free slots before they are retired, so re-added address can be
+        // connected up to the limit
+        for addr in self.backoff.remove(&old) {
+            self.aligner.put(addr);
+        }
         self.aligner.update(new, old);
         self.cur_address = new_addr;
+        self.reroute_parked();
     }
-    fn do_connect(&mut self) -> Option<SocketAddr> {
-        let ref blist = self.blist;
-        let new = self.aligner.get(self.conn_limit, |a| blist.is_failing(a));
+    fn check_for_config_updates(&mut self, cx: &mut Context) {
+        let mut new_config = None;
+        let mut finished = false;
+        if let Some(ref mut updates) = self.updates {
+            loop {
+                match updates.poll_next_unpin(cx) {
+                    Poll::Ready(Some(cfg)) => new_config = Some(cfg),
+                    Poll::Ready(None) => {
+                        finished = true;
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            }
+        }
+        if finished {
+            // keep the last config, finished stream must not be polled
+            self.updates = None;
+        }
+        if let Some(cfg) = new_config {
+            self.apply_config(cfg);
+        }
+    }
+    fn apply_config(&mut self, cfg: Config) {
+        debug!("New config {:?}", cfg);
+        self.reconnect_ms = cfg.reconnect_ms();
+        self.fail_timeout = cfg.fail_timeout;
+        self.park_timeout = cfg.park_timeout;
+        if cfg.conn_limit < self.conn_limit {
+            // retire connections that are above the limit, aligner
+            // is updated when they are actually closed
+            let mut per_addr = HashMap::new();
+            for task in &lock(&self.connections).all {
+                if task.is_closed() {
+                    continue;
+                }
+                let num = per_addr.entry(task.addr()).or_insert(0);
+                *num += 1;
+                if *num > cfg.conn_limit {
+                    task.close();
+                }
+            }
+        }
+        self.conn_limit = cfg.conn_limit;
+        self.rescan_parked = true;
+    }
+    fn do_connect(&mut self, cx: &mut Context,
+        excluded: &HashSet<AddrOf<C>>)
+        -> Option<AddrOf<C>>
+    {
+        let blist = &self.blist;
+        let hosts = &self.hosts;
+        let limit_task = &self.limit_task;
+        let new = self.aligner.get(self.conn_limit, |a| {
+            blist.is_failing(a) || excluded.contains(a) ||
+            // new connection to a host at its concurrency limit is useless
+            match hosts.get(a) {
+                Some(host) if host.is_saturated() => {
+                    limit_task.register(cx.waker());
+                    true
+                }
+                _ => false,
+            }
+        });
         if let Some(addr) = new {
             self.metrics.connection_attempt();
-            let task = Helper::new(addr, self.connections.clone());
-            self.connections.borrow_mut()
+            self.events.emit(Event::Connecting(addr.endpoint()));
+            let task = Helper::new(addr.clone(), self.connections.clone());
+            lock(&self.connections)
                 .all.insert(task.controller());
             self.futures.push(
-                Box::new(ConnectFuture::new(task,
-                    self.connector.connect(addr))));
+                Box::pin(ConnectFuture::new(task,
+                    self.connector.connect_with_timer(addr.clone(),
+                        &self.timer))));
             debug!("Connecting to {}", addr);
             return Some(addr);
         }
@@ -208,142 +497,758 @@ impl<A, C, E, M> Lazy<A, C, E, M>
     fn start_closing(&mut self) {
         if !self.closing {
             self.closing = true;
-            for conn in &self.connections.borrow_mut().all {
+            for conn in &lock(&self.connections).all {
                 conn.close();
             }
         }
     }
-    fn poll_futures(&mut self) {
+    fn retry_copy(&self, req: &Request<I>)
+        -> Option<I>
+    {
+        // copy is kept even if no attempts are left, so we can report
+        // that request is lost
+        match self.retry.settings() {
+            Some(_) => self.retry.copy(&req.item),
+            None => None,
+        }
+    }
+    /// Moves lost requests which should be retried to the requeue
+    fn check_lost(&mut self) {
+        let lost: Vec<_> = lock(&self.connections)
+            .lost.drain(..).collect();
+        for req in lost {
+            let allowed = match self.retry.settings() {
+                Some(s) => req.attempt < s.max_attempts,
+                None => false,
+            };
+            if allowed && self.budget.withdraw() {
+                self.metrics.request_retried();
+                lock(&self.connections).requeue.push_back(req);
+            } else {
+                self.metrics.retry_exhausted();
+            }
+        }
+    }
+    /// Dispatches requests returned by connections, returns false if
+    /// some of them are still waiting for a connection
+    fn dispatch_pending(&mut self, cx: &mut Context) -> bool {
+        self.check_lost();
+        self.report_limits();
+        loop {
+            let req = lock(&self.connections).requeue.pop_front();
+            match req {
+                Some(req) => match self.dispatch(cx, req) {
+                    AsyncSink::Ready => continue,
+                    AsyncSink::NotReady(req) => {
+                        lock(&self.connections)
+                            .requeue.push_front(req);
+                        return false;
+                    }
+                },
+                None => return true,
+            }
+        }
+    }
+    /// Tries to dispatch requests waiting for their hosts again
+    ///
+    /// Requests are only retried when some host might have become
+    /// available to them: a connection or a concurrency slot of an allowed
+    /// host is ready, or a connection might be established (e.g. address
+    /// is removed from blacklist).
+    fn dispatch_parked(&mut self, cx: &mut Context) {
+        self.expire_parked(cx);
+        let mut ready = HashSet::new();
+        for (addr, host) in &self.hosts {
+            if host.take_freed() {
+                ready.insert(addr.clone());
+            }
+        }
+        {
+            let mut conns = lock(&self.connections);
+            if mem::replace(&mut conns.ready_changed, false) {
+                ready.extend(conns.queue.iter().map(|c| c.addr()));
+            }
+        }
+        let rescan = mem::replace(&mut self.rescan_parked, false);
+        if !rescan && ready.is_empty() {
+            return;
+        }
+        let parked: Vec<_> = self.parked.drain(..).collect();
+        for parked in parked {
+            if !rescan &&
+                ready.iter().all(|a| parked.excluded.contains(a))
+            {
+                self.parked.push_back(parked);
+                continue;
+            }
+            let Parked { req, excluded, deadline } = parked;
+            let mut skipped = Vec::new();
+            let result = self.dispatch_to(cx, req, &excluded, &mut skipped);
+            lock(&self.connections).put_back(skipped);
+            if let AsyncSink::NotReady(mut req) = result {
+                req.slot = None;
+                self.parked.push_back(Parked { req, excluded, deadline });
+            }
+        }
+    }
+    /// Fails parked requests which wait longer than park timeout
+    ///
+    /// Timeout is the same for all requests, so the oldest one expires
+    /// first.
+    fn expire_parked(&mut self, cx: &mut Context) {
+        loop {
+            let deadline = match self.parked.front() {
+                Some(parked) => parked.deadline,
+                None => {
+                    self.park_sleep = None;
+                    return;
+                }
+            };
+            if matches!(self.park_sleep, Some((time, _)) if time != deadline) {
+                self.park_sleep = None;
+            }
+            let timer = &self.timer;
+            let (_, sleep) = self.park_sleep.get_or_insert_with(|| {
+                (deadline, timer.sleep_until(deadline))
+            });
+            let expired = deadline <= Instant::now() ||
+                sleep.poll_unpin(cx).is_ready();
+            if !expired {
+                return;
+            }
+            self.park_sleep = None;
+            let mut req = self.parked.pop_front()
+                .expect("parked request exists").req;
+            debug!("Request waited for its hosts for too long");
+            // treated like a request lost on a broken connection, so it's
+            // retried or dropped by `check_lost`
+            req.attempt += 1;
+            lock(&self.connections).lost.push_back(req);
+        }
+    }
+    /// Updates excluded hosts of parked requests when address changes
+    fn reroute_parked(&mut self) {
+        let parked: Vec<_> = self.parked.drain(..).collect();
+        for Parked { req, deadline, .. } in parked {
+            match self.excluded(&req.item) {
+                Ok(ref excluded) if excluded.is_empty() => {
+                    // hint is ignored now (e.g. excluded hosts are
+                    // removed), so request waits like any other one
+                    lock(&self.connections).requeue.push_back(req);
+                }
+                Ok(excluded) => {
+                    self.parked.push_back(Parked { req, excluded, deadline });
+                }
+                Err(pin) => {
+                    debug!("Dropping request pinned to {} which is \
+                            removed from the pool", pin);
+                    self.errors.unknown_address(pin);
+                    self.metrics.request_dropped();
+                }
+            }
+        }
+        self.rescan_parked = true;
+    }
+    /// Addresses request must not be sent to
+    ///
+    /// Returns an error if request is pinned to an unknown address
+    fn excluded(&self, item: &I)
+        -> Result<HashSet<AddrOf<C>>, AddrOf<C>>
+    {
+        if !self.route.enabled() {
+            return Ok(HashSet::new());
+        }
+        let all = &self.cur_address;
+        if all.is_empty() {
+            // no address yet, will wait for it anyway
+            return Ok(HashSet::new());
+        }
+        if let Some(pin) = self.route.pinned(item) {
+            if !all.contains(&pin) {
+                return Err(pin);
+            }
+            return Ok(all.iter().filter(|&a| *a != pin).cloned().collect());
+        }
+        let excluded = all.iter()
+            .filter(|a| self.route.is_excluded(item, a))
+            .cloned()
+            .collect::<HashSet<_>>();
+        if excluded.len() >= all.len() {
+            // hint is ignored if there is no other host
+            return Ok(HashSet::new());
+        }
+        Ok(excluded)
+    }
+    fn dispatch(&mut self, cx: &mut Context, req: Request<I>)
+        -> AsyncSink<Request<I>>
+    {
+        let excluded = match self.excluded(&req.item) {
+            Ok(excluded) => excluded,
+            Err(pin) => {
+                debug!("Dropping request pinned to {} which is \
+                        not in the pool", pin);
+                self.errors.unknown_address(pin);
+                self.metrics.request_dropped();
+                return AsyncSink::Ready;
+            }
+        };
+        let mut skipped = Vec::new();
+        let result = self.dispatch_to(cx, req, &excluded, &mut skipped);
+        lock(&self.connections).put_back(skipped);
+        match result {
+            AsyncSink::Ready => AsyncSink::Ready,
+            AsyncSink::NotReady(mut req) => {
+                // request handed back by a connection frees its slot
+                req.slot = None;
+                if !excluded.is_empty() && self.parked.len() < MAX_PARKED {
+                    // other requests shouldn't wait for the hosts this
+                    // one is restricted to
+                    self.parked.push_back(Parked {
+                        req, excluded,
+                        deadline: Instant::now() + self.park_timeout,
+                    });
+                    return AsyncSink::Ready;
+                }
+                AsyncSink::NotReady(req)
+            }
+        }
+    }
+    /// Reports concurrency limits changed since the last call
+    fn report_limits(&self) {
+        for (addr, host) in &self.hosts {
+            if let Some(limit) = host.take_changed() {
+                self.metrics.concurrency_limit(&addr.endpoint(), limit);
+            }
+        }
+    }
+    /// Dispatches request to a connection
+    ///
+    /// Requests aren't sent to hosts in `excluded` set, hosts which are
+    /// at their concurrency limit are skipped too.
+    fn dispatch_to(&mut self, cx: &mut Context, mut req: Request<I>,
+        excluded: &HashSet<AddrOf<C>>,
+        skipped: &mut Vec<Controller<I, AddrOf<C>>>)
+        -> AsyncSink<Request<I>>
+    {
+        'outer: loop {
+            loop {
+                let ctr = lock(&self.connections).next();
+                if let Some(ctr) = ctr {
+                    if ctr.is_closed() { continue }
+                    let addr = ctr.addr();
+                    if excluded.contains(&addr) {
+                        skipped.push(ctr);
+                        continue;
+                    }
+                    let slot = match self.hosts.get(&addr) {
+                        Some(host) => match Host::acquire(host) {
+                            Some(slot) => Some(slot),
+                            None => {
+                                // woken up when some request is completed
+                                self.limit_task.register(cx.waker());
+                                skipped.push(ctr);
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    // copy is made after updating routing info, so retry
+                    // knows which host request was sent to
+                    self.route.dispatched(&mut req.item, &addr);
+                    req.copy = self.retry_copy(&req);
+                    // copy doesn't hold the slot, it gets its own on retry
+                    if let Some((completion, slot)) = slot {
+                        self.limit.track(&mut req.item, completion);
+                        req.slot = Some(slot);
+                    }
+                    ctr.request(req);
+                    self.poll_futures(cx);
+                    if let Some(request) = ctr.request_back() {
+                        req = request;
+                        continue;
+                    } else {
+                        #[cfg(feature="tracing")]
+                        ::tracing::debug!(parent: &ctr.span(),
+                            addr=%ctr.addr(), "request dispatched");
+                        // Note: we assume that controller put itself back
+                        // to the active queue
+                        self.health = Health::Healthy;
+                        return AsyncSink::Ready;
+                    }
+                } else {
+                    self.poll_futures(cx);
+                    if !lock(&self.connections).has_ready() {
+                        break;
+                    }
+                }
+            }
+            loop {
+                while let Some(addr) = self.do_connect(cx, excluded) {
+                    self.poll_futures(cx);
+                    if lock(&self.connections).has_ready() {
+                        continue 'outer;
+                    }
+                    if !self.blist.is_failing(&addr) &&
+                        !self.backoff.is_waiting(&addr)
+                    {
+                        // Waiting for connect
+                        return AsyncSink::NotReady(req);
+                    }
+                }
+                if self.blist.poll(cx).is_ready() {
+                    self.metrics.blacklist_remove();
+                    while self.blist.poll(cx).is_ready() {
+                        self.metrics.blacklist_remove();
+                    }
+                    self.rescan_parked = true;
+                } else if self.poll_backoff(cx) {
+                    // slots are freed, connect again
+                } else {
+                    if !self.has_healthy(excluded) &&
+                        self.should_fail(cx, excluded)
+                    {
+                        debug!("Failing request: no healthy backends");
+                        self.metrics.request_rejected();
+                        self.fail.reject(req.item);
+                        return AsyncSink::Ready;
+                    }
+                    return AsyncSink::NotReady(req);
+                }
+            }
+        }
+    }
+    /// Returns true if there is a connection (or a connection attempt)
+    /// request might be sent to
+    fn has_healthy(&self, excluded: &HashSet<AddrOf<C>>) -> bool {
+        lock(&self.connections).all.iter()
+            .any(|c| !c.is_closed() && !excluded.contains(&c.addr()))
+    }
+    /// Called when there are no healthy backends for a request, returns
+    /// true if it should be failed
+    ///
+    /// Requests with routing hints don't change health of the pool, they
+    /// are only failed when the whole pool is failing.
+    fn should_fail(&mut self, cx: &mut Context,
+        excluded: &HashSet<AddrOf<C>>)
+        -> bool
+    {
+        if excluded.is_empty() {
+            return self.no_backends(cx);
+        }
+        matches!(self.health, Health::Failing)
+    }
+    /// Called when there are no healthy backends, returns true if
+    /// requests should be failed
+    fn no_backends(&mut self, cx: &mut Context) -> bool {
+        let timeout = match self.fail.timeout() {
+            Some(t) => self.fail_timeout.unwrap_or(t),
+            // requests wait, so there is nothing to report
+            None => return false,
+        };
+        if let Health::Healthy = self.health {
+            if !self.cur_address.is_empty() {
+                self.errors.no_healthy_backends();
+            }
+            self.health = if timeout == Duration::new(0, 0) {
+                Health::Failing
+            } else {
+                Health::Unhealthy(
+                    self.timer.sleep_until(Instant::now() + timeout))
+            };
+        }
+        let expired = match self.health {
+            Health::Healthy => unreachable!(),
+            Health::Unhealthy(ref mut sleep) => {
+                sleep.poll_unpin(cx).is_ready()
+            }
+            Health::Failing => return true,
+        };
+        if expired {
+            self.health = Health::Failing;
+        }
+        expired
+    }
+    /// Frees connection slots after initialization errors, returns true
+    /// if any slot is freed
+    fn poll_backoff(&mut self, cx: &mut Context) -> bool {
+        let mut freed = false;
+        while let Poll::Ready(addr) = self.backoff.poll(cx) {
+            self.aligner.put(addr);
+            freed = true;
+        }
+        self.rescan_parked |= freed;
+        freed
+    }
+    fn reconnect_time(&self) -> Instant {
+        let (min, max) = self.reconnect_ms;
+        let dur = Duration::from_millis(if min < max {
+            thread_rng().gen_range(min, max)
+        } else {
+            min
+        });
+        Instant::now() + dur
+    }
+    /// Polls connections and dispatches requests waiting for them,
+    /// returns true if no such requests are left
+    fn poll_dispatch(&mut self, cx: &mut Context) -> bool {
+        self.check_for_config_updates(cx);
+        self.check_for_address_updates(cx);
+        if self.closing {
+            // address stream is closed
+            return true;
+        }
+        self.poll_futures(cx);
+        while self.blist.poll(cx).is_ready() {
+            self.metrics.blacklist_remove();
+            self.rescan_parked = true;
+        }
+        self.poll_backoff(cx);
+        self.dispatch_parked(cx);
+        self.dispatch_pending(cx) && self.parked.is_empty()
+    }
+    fn poll_futures(&mut self, cx: &mut Context) {
         loop {
-            match self.futures.poll() {
-                Ok(Async::NotReady) => break,
-                Ok(Async::Ready(None)) => break,
-                Ok(Async::Ready(Some(FutureOk::Connected(task, sink)))) => {
+            match self.futures.poll_next_unpin(cx) {
+                Poll::Pending => break,
+                Poll::Ready(None) => break,
+                Poll::Ready(Some(Ok(FutureOk::Connected(task, sink)))) => {
                     self.metrics.connection();
+                    self.events.emit(Event::Connected(task.addr().endpoint()));
                     debug!("Connected to {}", task.addr());
                     // helper will add itself to the active queue on wakeup
-                    self.futures.push(Box::new(SinkFuture::new(sink, task)));
+                    self.futures.push(Box::pin(SinkFuture::new(sink, task)));
                 }
-                Err(FutureErr::CantConnect(sa, err)) => {
+                Poll::Ready(Some(Err(FutureErr::CantConnect(sa, err)))) => {
+                    let until = self.reconnect_time();
+                    if self.connector.is_initialize_error(&err) {
+                        // only this connection waits, as the address
+                        // itself is reachable
+                        self.metrics.initialize_error();
+                        self.errors.connection_error(sa.clone(), err);
+                        self.events.emit(Event::Disconnected(sa.endpoint(),
+                            DisconnectReason::CantInitialize));
+                        self.backoff.delay(sa, until);
+                        continue;
+                    }
                     self.metrics.connection_error();
-                    self.errors.connection_error(sa, err);
-                    let (min, max) = self.reconnect_ms;
-                    let dur = Duration::from_millis(
-                            thread_rng().gen_range(min, max));
+                    self.errors.connection_error(sa.clone(), err);
+                    self.events.emit(Event::Disconnected(sa.endpoint(),
+                        DisconnectReason::CantConnect));
                     self.metrics.blacklist_add();
-                    self.blist.blacklist(sa, Instant::now() + dur);
+                    self.events.emit(Event::Blacklisted(sa.endpoint(), until));
+                    self.blist.blacklist(sa.clone(), until);
                     self.aligner.put(sa);
                 }
-                Err(FutureErr::Disconnected(sa, err)) => {
+                Poll::Ready(Some(Err(FutureErr::Disconnected(sa, err))))
+                => {
                     self.metrics.disconnect();
                     // TODO(tailhook) blacklist connection if it was
                     // recently connected
-                    self.errors.sink_error(sa, err);
+                    self.errors.sink_error(sa.clone(), err);
+                    self.events.emit(Event::Disconnected(sa.endpoint(),
+                        DisconnectReason::Error));
                     self.aligner.put(sa);
+                    // parked requests might connect to this address again
+                    self.rescan_parked = true;
                 }
-                Ok(Async::Ready(Some(FutureOk::Aborted(_)))) => {
+                Poll::Ready(Some(Ok(FutureOk::Aborted(sa)))) => {
                     self.metrics.connection_abort();
+                    self.events.emit(Event::Disconnected(sa.endpoint(),
+                        DisconnectReason::Aborted));
+                    // no-op if address is removed, frees a slot if
+                    // connection is retired because of new conn_limit
+                    self.aligner.put(sa);
+                    self.rescan_parked = true;
                 }
-                Ok(Async::Ready(Some(FutureOk::Closed(_)))) => {
+                Poll::Ready(Some(Ok(FutureOk::Closed(sa)))) => {
                     self.metrics.disconnect();
+                    self.events.emit(Event::Disconnected(sa.endpoint(),
+                        DisconnectReason::Closed));
+                    self.aligner.put(sa);
+                    self.rescan_parked = true;
                 }
             }
         }
     }
 }
 
-impl<A, C, E, M> Sink for Lazy<A, C, E, M>
-    where A: Stream<Item=Address, Error=Void>,
-          C: Connect + 'static,
-          <C::Future as Future>::Item: Sink,
-          E: ErrorLog<
-            ConnectionError=<C::Future as Future>::Error,
-            SinkError=<<C::Future as Future>::Item as Sink>::SinkError>,
+impl<I, A, C, E, M, R, H, F, L> Mux<I>
+    for Lazy<I, A, C, E, M, R, H, F, L>
+    where A: Stream + Send + 'static,
+          A::Item: AddressSet<Addr=<C as Connect>::Address>,
+          C: Connect + Send + 'static,
+          C::Future: Send + 'static,
+          C::Connection: Sink<I> + Send + 'static,
+          C::Error: Send + 'static,
+          <C::Connection as Sink<I>>::Error: Send + 'static,
+          I: Send + 'static,
+          E: ErrorLog<<C as Connect>::Address,
+            ConnectionError=C::Error,
+            SinkError=<C::Connection as Sink<I>>::Error,
+            >,
+          E: 'static,
           M: Collect + 'static,
+          R: RetryPolicy<I>,
+          H: RoutePolicy<I, C::Address>,
+          F: FailPolicy<I>,
+          L: LimitPolicy<I>,
 {
-    type SinkItem = <<C::Future as Future>::Item as Sink>::SinkItem;
-    type SinkError = private::Done;
-    fn start_send(&mut self, mut v: Self::SinkItem)
-        -> Result<AsyncSink<Self::SinkItem>, private::Done>
+    fn start_send(&mut self, cx: &mut Context, v: I)
+        -> Result<AsyncSink<I>, private::Done>
     {
         if self.closing {
-            self.poll_futures();
-            if self.futures.len() == 0 {
+            self.poll_futures(cx);
+            if self.futures.is_empty() {
                 return Err(private::Done);
             }
             return Ok(AsyncSink::NotReady(v));
         } else {
-            self.check_for_address_updates();
-            'outer: loop {
-                loop {
-                    let ctr = self.connections.borrow_mut().next();
-                    if let Some(ctr) = ctr {
-                        if ctr.is_closed() { continue }
-                        ctr.request(v);
-                        self.poll_futures();
-                        if let Some(request) = ctr.request_back() {
-                            v = request;
-                            continue;
-                        } else {
-                            // Note: we assume that controller put itself back
-                            // to the active queue
-                            return Ok(AsyncSink::Ready);
-                        }
-                    } else {
-                        self.poll_futures();
-                        if !self.connections.borrow().has_ready() {
-                            break;
-                        }
-                    }
-                }
-                loop {
-                    while let Some(addr) = self.do_connect() {
-                        self.poll_futures();
-                        if self.connections.borrow().has_ready() {
-                            continue 'outer;
-                        }
-                        if !self.blist.is_failing(addr) {
-                            // Waiting for connect
-                            return Ok(AsyncSink::NotReady(v));
-                        }
-                    }
-                    if let Async::Ready(_) = self.blist.poll() {
-                        self.metrics.blacklist_remove();
-                        while let Async::Ready(_) = self.blist.poll() {
-                            self.metrics.blacklist_remove();
-                        }
-                    } else {
-                        // log backpressure issue, not sure how
-                        return Ok(AsyncSink::NotReady(v));
-                    }
+            self.check_for_config_updates(cx);
+            self.check_for_address_updates(cx);
+            if !self.dispatch_pending(cx) {
+                return Ok(AsyncSink::NotReady(v));
+            }
+            let req = Request {
+                item: v,
+                copy: None,
+                attempt: 0,
+                slot: None,
+            };
+            match self.dispatch(cx, req) {
+                AsyncSink::Ready => {
+                    self.budget.deposit();
+                    Ok(AsyncSink::Ready)
                 }
+                AsyncSink::NotReady(req) => Ok(AsyncSink::NotReady(req.item)),
             }
         }
     }
-    fn poll_complete(&mut self) -> Result<Async<()>, private::Done> {
+    fn poll_complete(&mut self, cx: &mut Context)
+        -> Poll<Result<(), private::Done>>
+    {
+        if !self.closing {
+            self.poll_dispatch(cx);
+        }
         if self.closing {
-            self.poll_futures();
-            if self.futures.len() == 0 {
-                return Err(private::Done);
-            }
-            return Ok(Async::NotReady);
-        } else {
-            self.poll_futures();
-            while let Async::Ready(_) = self.blist.poll() {
-                self.metrics.blacklist_remove();
+            self.poll_futures(cx);
+            if self.futures.is_empty() {
+                return Poll::Ready(Err(private::Done));
             }
         }
         // TODO(tailhook) maybe we can track if connections have everything
         // flushed
-        return Ok(Async::NotReady);
+        return Poll::Pending;
     }
-    fn close(&mut self) -> Result<Async<()>, private::Done> {
-        self.start_closing();
-        self.poll_futures();
-        if self.futures.len() == 0 {
-            return Ok(Async::Ready(()));
+    fn close(&mut self, cx: &mut Context) -> Poll<Result<(), private::Done>> {
+        if !self.closing {
+            // parked and requeued requests are delivered before closing
+            // connections, if it takes longer than graceful shutdown
+            // timeout the queue drops the whole mux
+            if !self.poll_dispatch(cx) {
+                return Poll::Pending;
+            }
+            self.start_closing();
         }
-        return Ok(Async::NotReady);
+        self.poll_futures(cx);
+        if self.futures.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        return Poll::Pending;
     }
 }
 
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+
+    use futures::{FutureExt, Sink, SinkExt, StreamExt};
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use futures::future::{pending, ready, Either};
+    use tokio::runtime::Runtime;
+
+    use config::NewMetrics;
+    use error_log::ShutdownReason;
+    use events::{Event, DisconnectReason};
+    use metrics::Collect;
+    use pool_for;
+    use queue::PoolError;
+    use route::Route;
+    use timer::{Timer, Sleep};
+
+    struct Record(Arc<Mutex<Vec<u32>>>);
+
+    /// A timer which never waits
+    struct Expired;
+
+    /// Counts requests dropped after all retries
+    #[derive(Clone)]
+    struct Exhausted(Arc<AtomicUsize>);
+
+    /// A request which must be sent to a specific host
+    struct Pinned(u32, SocketAddr);
+
+    impl Route for Pinned {
+        fn pinned(&self) -> Option<SocketAddr> {
+            Some(self.1)
+        }
+    }
+
+    impl From<Pinned> for u32 {
+        fn from(req: Pinned) -> u32 {
+            req.0
+        }
+    }
+
+    impl Timer for Expired {
+        fn sleep_until(&self, _deadline: Instant) -> Sleep {
+            Box::pin(ready(()))
+        }
+    }
+
+    impl Collect for Exhausted {
+        fn retry_exhausted(&self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl NewMetrics for Exhausted {
+        type Collect = Exhausted;
+        fn construct(self, _name: &str) -> Exhausted {
+            self
+        }
+    }
+
+    impl<T: Into<u32>> Sink<T> for Record {
+        type Error = io::Error;
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+        fn start_send(self: Pin<&mut Self>, item: T)
+            -> Result<(), io::Error>
+        {
+            self.0.lock().unwrap().push(item.into());
+            Ok(())
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context)
+            -> Poll<Result<(), io::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn initialize_error() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let conn = sent.clone();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let (mut pool, future) = pool_for(move |_: SocketAddr| {
+                ready(Ok::<_, io::Error>(Record(conn.clone())))
+            })
+            .initialize(move |_: SocketAddr, conn: Record| {
+                ready(if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Err("denied")
+                } else {
+                    Ok(conn)
+                })
+            })
+            .connect_to_static(&[addr])
+            .lazy_uniform_connections(1)
+            .build(Expired);
+        let events = pool.events();
+        block_on(pool.send(7u32)).ok().unwrap();
+        drop(pool);
+        // backoff after the initialization error expires immediately, and
+        // the request is delivered before pool is closed
+        block_on(future);
+        let events = block_on(events.collect::<Vec<_>>());
+        assert!(events.iter().any(|e| matches!(*e,
+            Event::Disconnected(_, DisconnectReason::CantInitialize))));
+        assert!(events.iter().any(|e| matches!(*e, Event::Connected(..))));
+        assert!(!events.iter().any(|e| matches!(*e, Event::Blacklisted(..))));
+        assert_eq!(*sent.lock().unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn graceful_shutdown_delivers_parked() {
+        let rt = Runtime::new().unwrap();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let conn = sent.clone();
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let (gate_tx, gate) = oneshot::channel::<()>();
+        let gate = gate.shared();
+        let (mut pool, shutdown) = pool_for(move |addr: SocketAddr| {
+                let conn = conn.clone();
+                if addr == b {
+                    // connection to `b` is established on request
+                    Either::Left(gate.clone()
+                        .map(move |_| Ok::<_, io::Error>(Record(conn))))
+                } else {
+                    Either::Right(ready(Ok(Record(conn))))
+                }
+            })
+            .connect_to_static(&[a, b])
+            .lazy_uniform_connections(1)
+            .routing()
+            .spawn_on(rt.handle());
+        rt.block_on(pool.send(Pinned(7, b))).ok().unwrap();
+        // let the request be parked waiting for the connection
+        rt.block_on(rt.handle()
+            .sleep_until(Instant::now() + Duration::from_millis(10)));
+        let closed = shutdown.graceful(Duration::from_secs(10));
+        rt.block_on(rt.handle()
+            .sleep_until(Instant::now() + Duration::from_millis(10)));
+        assert!(sent.lock().unwrap().is_empty());
+        // shutdown isn't reported while requests are being delivered
+        rt.block_on(pool.flush()).ok().unwrap();
+        gate_tx.send(()).unwrap();
+        rt.block_on(closed);
+        assert_eq!(*sent.lock().unwrap(), vec![7]);
+        assert!(matches!(rt.block_on(pool.flush()),
+            Err(PoolError::Shutdown(ShutdownReason::Requested))));
+    }
+
+    #[test]
+    fn park_timeout() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let conn = sent.clone();
+        let exhausted = Arc::new(AtomicUsize::new(0));
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let (mut pool, future) = pool_for(move |addr: SocketAddr| {
+                if addr == b {
+                    Either::Left(pending())
+                } else {
+                    Either::Right(ready(Ok::<_, io::Error>(
+                        Record(conn.clone()))))
+                }
+            })
+            .connect_to_static(&[a, b])
+            .lazy_uniform_connections(1)
+            .routing()
+            .metrics(Exhausted(exhausted.clone()))
+            .build(Expired);
+        block_on(pool.send(Pinned(7, b))).ok().unwrap();
+        drop(pool);
+        // request can't reach `b`, so it expires instead of blocking
+        // shutdown of the pool
+        block_on(future);
+        assert!(sent.lock().unwrap().is_empty());
+        assert_eq!(exhausted.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,35 +1,72 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use abstract_ns::Address;
-use futures::{Future, Sink};
+use futures::{Sink, Stream};
 use futures::stream::FuturesUnordered;
+use futures::task::AtomicWaker;
 
-use error_log::{ErrorLog};
+use concurrency::Host;
+use retry::Budget;
+use events::Subscribers;
 use connect::Connect;
 use uniform::aligner::Aligner;
-use uniform::failures::Blacklist;
-use uniform::{Connections, FutureOk, FutureErr};
+use uniform::failures::{Blacklist, Backoff};
+use timer::{SharedTimer, Sleep};
+use uniform::{Config, Connections, FutureOk, FutureErr, Health, Parked};
 
 
-pub struct Lazy<A, C, E, M>
-    where E: ErrorLog,
-          C: Connect,
-          <<C as Connect>::Future as Future>::Item: Sink,
+/// Connection attempt or a working connection of the pool
+pub(in uniform) type ConnFuture<I, C> = Pin<Box<dyn Future<Output=Result<
+        FutureOk<<C as Connect>::Connection, I, <C as Connect>::Address>,
+        FutureErr<<C as Connect>::Error,
+                  <<C as Connect>::Connection as Sink<I>>::Error,
+                  <C as Connect>::Address>,
+    >> + Send>>;
+
+pub struct Lazy<I, A, C, E, M, R, H, F, L>
+    where C: Connect,
+          C::Connection: Sink<I>,
 {
     pub(in uniform) conn_limit: u32,
     pub(in uniform) reconnect_ms: (u64, u64),  // easier to make random value
-    pub(in uniform) futures: FuturesUnordered<Box<Future<
-                        Item=FutureOk<<C::Future as Future>::Item>,
-                        Error=FutureErr<E::ConnectionError, E::SinkError>>>>,
-    pub(in uniform) connections: Rc<RefCell<Connections<
-                        <<<C as Connect>::Future as Future>::Item as Sink>::SinkItem>>>,
-    pub(in uniform) address: A,
+    /// Overrides timeout of fail policy (see `Config::fail_timeout`)
+    pub(in uniform) fail_timeout: Option<Duration>,
+    /// Time requests with routing hints wait for their hosts
+    pub(in uniform) park_timeout: Duration,
+    pub(in uniform) futures: FuturesUnordered<ConnFuture<I, C>>,
+    pub(in uniform) connections: Arc<Mutex<Connections<I, C::Address>>>,
+    pub(in uniform) updates:
+        Option<Pin<Box<dyn Stream<Item=Config> + Send>>>,
+    pub(in uniform) address: Pin<Box<A>>,
     pub(in uniform) connector: C,
     pub(in uniform) errors: E,
     pub(in uniform) metrics: M,
-    pub(in uniform) aligner: Aligner,
-    pub(in uniform) blist: Blacklist,
-    pub(in uniform) cur_address: Address,
+    pub(in uniform) events: Subscribers,
+    pub(in uniform) aligner: Aligner<C::Address>,
+    pub(in uniform) blist: Blacklist<C::Address>,
+    /// Connection slots waiting after initialization errors
+    pub(in uniform) backoff: Backoff<C::Address>,
+    pub(in uniform) cur_address: HashSet<C::Address>,
+    /// Requests with routing hints waiting for a connection to one of
+    /// the hosts they are allowed to be sent to
+    pub(in uniform) parked: VecDeque<Parked<I, C::Address>>,
+    /// Wakes up the task when the oldest parked request expires
+    pub(in uniform) park_sleep: Option<(Instant, Sleep)>,
+    /// Some host might have become available to any parked request
+    pub(in uniform) rescan_parked: bool,
     pub(in uniform) closing: bool,
+    pub(in uniform) retry: R,
+    pub(in uniform) route: H,
+    pub(in uniform) fail: F,
+    pub(in uniform) limit: L,
+    /// Concurrency limits of hosts (only if limit is enabled)
+    pub(in uniform) hosts: HashMap<C::Address, Arc<Host>>,
+    /// Task waiting for a host to free a slot
+    pub(in uniform) limit_task: Arc<AtomicWaker>,
+    pub(in uniform) health: Health,
+    pub(in uniform) timer: SharedTimer,
+    pub(in uniform) budget: Budget,
 }
@@ -1,89 +1,145 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use futures::{Future, Async, Sink, AsyncSink};
+use futures::Sink;
 
+use address::PoolAddress;
 use uniform::{FutureOk, FutureErr};
-use uniform::chan::{Action, Helper};
+use uniform::chan::{Action, Helper, Request};
 
 
-pub(in uniform) struct SinkFuture<S, E>
-    where S: Sink,
-{
-    sink: S,
-    task: Helper<S::SinkItem>,
-    phantom: PhantomData<*const E>,
+pub(in uniform) struct SinkFuture<S, I, E, P> {
+    sink: Pin<Box<S>>,
+    task: Helper<I, P>,
+    /// Copies of requests which are not flushed yet
+    unflushed: VecDeque<Request<I>>,
+    phantom: PhantomData<fn(E)>,
+    #[cfg(feature="tracing")]
+    span: ::tracing::Span,
 }
 
-impl<S: Sink, E> SinkFuture<S, E> {
-    pub fn new(sink: S, task: Helper<S::SinkItem>)
-        -> SinkFuture<S, E>
+// sink is pinned in a box, other fields are never pinned
+impl<S, I, E, P> Unpin for SinkFuture<S, I, E, P> {}
+
+impl<S: Sink<I>, I, E, P: PoolAddress> SinkFuture<S, I, E, P> {
+    pub fn new(sink: S, task: Helper<I, P>)
+        -> SinkFuture<S, I, E, P>
     {
-        SinkFuture { sink, task, phantom: PhantomData }
+        SinkFuture {
+            #[cfg(feature="tracing")]
+            span: task.span(),
+            sink: Box::pin(sink),
+            task,
+            unflushed: VecDeque::new(),
+            phantom: PhantomData,
+        }
     }
 }
 
-impl<S: Sink, E> Future for SinkFuture<S, E> {
-    type Item = FutureOk<S>;
-    type Error = FutureErr<E, S::SinkError>;
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        match self.task.take() {
-            Action::StartSend(item) => match self.sink.start_send(item) {
-                Ok(AsyncSink::Ready) => {
-                    // We need to flush data immediately because there is
-                    // no way to schedule a wakeup on poll_complete of parent
-                    // schedule
-                    //
-                    // TODO(tailhook) we might want to fix it by wrapping the
-                    //                future into a refcell and calling
-                    //                start_send manually instead of through
-                    //                futures unordered.
-                    match self.sink.poll_complete() {
-                        Ok(_)  => {
-                            // By contract there is no difference in Ready and
-                            // NotReady I.e. both of them may mean that another
-                            // element can be pushed now. They only distinquish
-                            // whether there is something left in the buffer.
-                            self.task.requeue();
-                            Ok(Async::NotReady)
+impl<S: Sink<I>, I, E, P: PoolAddress> SinkFuture<S, I, E, P> {
+    fn keep_copy(&mut self, copy: Option<I>, attempt: u32) {
+        if let Some(item) = copy {
+            // pipelined sinks might never be flushed completely, so we
+            // assume that the oldest request is delivered by now
+            let limit = self.task.max_unflushed();
+            if limit == 0 {
+                return;
+            }
+            if self.unflushed.len() >= limit {
+                self.unflushed.pop_front();
+            }
+            self.unflushed.push_back(Request {
+                item,
+                copy: None,
+                attempt: attempt + 1,
+                slot: None,
+            });
+        }
+    }
+    fn disconnected(&mut self, e: S::Error)
+        -> Poll<Result<FutureOk<S, I, P>, FutureErr<E, S::Error, P>>>
+    {
+        self.task.closed();
+        self.task.lost(&mut self.unflushed);
+        Poll::Ready(Err(FutureErr::Disconnected(self.task.addr(), e)))
+    }
+    fn flush(&mut self, cx: &mut Context)
+        -> Poll<Result<FutureOk<S, I, P>, FutureErr<E, S::Error, P>>>
+    {
+        match self.sink.as_mut().poll_flush(cx) {
+            Poll::Ready(Err(e)) => self.disconnected(e),
+            flushed => {
+                // Pending doesn't mean that another element can't be
+                // pushed now, it only means that there is something left
+                // in the buffer, readiness is checked by `poll_ready`
+                if flushed.is_ready() {
+                    self.unflushed.clear();
+                }
+                self.task.requeue(cx);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S: Sink<I>, I, E, P: PoolAddress> Future for SinkFuture<S, I, E, P> {
+    type Output = Result<FutureOk<S, I, P>, FutureErr<E, S::Error, P>>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        #[cfg(feature="tracing")]
+        let span = this.span.clone();
+        #[cfg(feature="tracing")]
+        let _enter = span.enter();
+        match this.task.take() {
+            Action::StartSend(req) => match this.sink.as_mut().poll_ready(cx)
+            {
+                Poll::Ready(Ok(())) => {
+                    let Request { item, copy, attempt, slot } = req;
+                    match this.sink.as_mut().start_send(item) {
+                        Ok(()) => {
+                            this.keep_copy(copy, attempt);
+                            if let Some(slot) = slot {
+                                slot.accepted();
+                            }
+                            // We need to flush data immediately because
+                            // there is no way to schedule a wakeup on
+                            // poll_complete of parent schedule
+                            //
+                            // TODO(tailhook) we might want to fix it by
+                            //                wrapping the future into a
+                            //                refcell and calling
+                            //                start_send manually instead
+                            //                of through futures unordered.
+                            this.flush(cx)
                         }
                         Err(e) => {
-                            self.task.closed();
-                            Err(FutureErr::Disconnected(self.task.addr(), e))
+                            this.keep_copy(copy, attempt);
+                            this.disconnected(e)
                         }
                     }
                 }
-                Ok(AsyncSink::NotReady(item)) => {
-                    self.task.backpressure(item);
-                    Ok(Async::NotReady)
+                Poll::Pending => {
+                    this.task.backpressure(req);
+                    Poll::Pending
                 }
-                Err(e) => {
-                    self.task.closed();
-                    Err(FutureErr::Disconnected(self.task.addr(), e))
+                Poll::Ready(Err(e)) => {
+                    // request was never sent, so it's dispatched again
+                    // when the helper is dropped
+                    this.task.backpressure(req);
+                    this.disconnected(e)
                 }
             }
-            Action::Poll => match self.sink.poll_complete() {
-                Ok(_)  => {
-                    // By contract there is no difference in Ready and NotReady
-                    // I.e. both of them may mean that another element can
-                    // be pushed now. They only distinquish whether there is
-                    // something left in the buffer.
-                    self.task.requeue();
-                    Ok(Async::NotReady)
-                }
-                Err(e) => {
-                    self.task.closed();
-                    Err(FutureErr::Disconnected(self.task.addr(), e))
-                }
-            }
-            Action::Close => match self.sink.close() {
-                Ok(Async::Ready(()))  => {
-                    Ok(Async::Ready(FutureOk::Closed(self.task.addr())))
-                }
-                Ok(Async::NotReady)  => Ok(Async::NotReady),
-                Err(e) => {
-                    self.task.closed();
-                    Err(FutureErr::Disconnected(self.task.addr(), e))
+            Action::Poll => this.flush(cx),
+            Action::Close => match this.sink.as_mut().poll_close(cx) {
+                Poll::Ready(Ok(()))  => {
+                    this.unflushed.clear();
+                    Poll::Ready(Ok(FutureOk::Closed(this.task.addr())))
                 }
+                Poll::Pending  => Poll::Pending,
+                Poll::Ready(Err(e)) => this.disconnected(e),
             }
         }
     }